@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Lists the NTFS alternate data streams of a file as `:name:$DATA`, leaving
+/// out the unnamed main stream. `fs::copy` only copies the main stream.
+#[cfg(windows)]
+pub fn alternate_streams(path: &Path) -> Result<Vec<OsString>> {
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use windows_sys::Win32::{
+        Foundation::{ERROR_HANDLE_EOF, INVALID_HANDLE_VALUE},
+        Storage::FileSystem::{
+            FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+            WIN32_FIND_STREAM_DATA,
+        },
+    };
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut data = WIN32_FIND_STREAM_DATA::default();
+    // SAFETY: `wide` is NUL-terminated and `data` is the struct that
+    // `FindStreamInfoStandard` fills in.
+    let handle = unsafe {
+        FindFirstStreamW(
+            wide.as_ptr(),
+            FindStreamInfoStandard,
+            (&raw mut data).cast(),
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        let err = io::Error::last_os_error();
+        // Reported for files without any stream, such as empty directories.
+        if err.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+            return Ok(Vec::new());
+        }
+        return Err(err).with_context(|| format!("listing streams of {}", path.display()));
+    }
+
+    let mut streams = Vec::new();
+    let result = loop {
+        let len = data
+            .cStreamName
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(data.cStreamName.len());
+        let name = OsString::from_wide(&data.cStreamName[..len]);
+        if name != "::$DATA" {
+            streams.push(name);
+        }
+        // SAFETY: `handle` is a live stream search handle.
+        if unsafe { FindNextStreamW(handle, (&raw mut data).cast()) } == 0 {
+            break io::Error::last_os_error();
+        }
+    };
+    // SAFETY: `handle` came from `FindFirstStreamW` and is closed only here.
+    unsafe { FindClose(handle) };
+    if result.raw_os_error() != Some(ERROR_HANDLE_EOF as i32) {
+        return Err(result).with_context(|| format!("listing streams of {}", path.display()));
+    }
+    Ok(streams)
+}
+
+/// Other platforms have no alternate data streams.
+#[cfg(not(windows))]
+pub fn alternate_streams(_path: &Path) -> Result<Vec<OsString>> {
+    Ok(Vec::new())
+}
+
+/// Copies the named `streams` of `src` onto `dst`, returning the bytes copied.
+pub fn copy_streams(src: &Path, dst: &Path, streams: &[OsString]) -> Result<u64> {
+    let mut bytes = 0;
+    for stream in streams {
+        let from = stream_path(src, stream);
+        let to = stream_path(dst, stream);
+        let mut reader =
+            File::open(&from).with_context(|| format!("opening {}", from.display()))?;
+        let mut writer = File::create(&to).with_context(|| format!("creating {}", to.display()))?;
+        bytes += io::copy(&mut reader, &mut writer)
+            .with_context(|| format!("copying {}", from.display()))?;
+    }
+    Ok(bytes)
+}
+
+/// Addresses a stream of `file`: `file.txt` + `:hidden:$DATA`.
+fn stream_path(file: &Path, stream: &OsString) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(stream);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_path() {
+        assert_eq!(
+            stream_path(Path::new("dir/file.txt"), &OsString::from(":hidden:$DATA")),
+            PathBuf::from("dir/file.txt:hidden:$DATA")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_backup_directory_copies_streams_with_include_ads() {
+        use crate::{backup_directory_with, BackupOptions};
+        use std::fs;
+        use tempfile::TempDir;
+
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(src_dir.join("file.txt"), b"main").unwrap();
+        fs::write(src_dir.join("file.txt:hidden"), b"secret").unwrap();
+        assert_eq!(
+            alternate_streams(&src_dir.join("file.txt")).unwrap(),
+            [":hidden:$DATA"]
+        );
+
+        let dropped = tmp.path().join("dropped");
+        backup_directory_with(&src_dir, &dropped, &BackupOptions::default()).unwrap();
+        assert!(alternate_streams(&dropped.join("file.txt"))
+            .unwrap()
+            .is_empty());
+
+        let kept = tmp.path().join("kept");
+        let opts = BackupOptions {
+            include_ads: true,
+            ..Default::default()
+        };
+        backup_directory_with(&src_dir, &kept, &opts).unwrap();
+        assert_eq!(fs::read(kept.join("file.txt:hidden")).unwrap(), b"secret");
+    }
+}
@@ -0,0 +1,494 @@
+use crate::{
+    manifest::{hash_file, manifest_key, Manifest, ManifestEntry, MANIFEST_NAME},
+    ownership::OwnerMap,
+    BackupOptions, BackupStats, DryRunMode,
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::Deserialize;
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Component, Path, PathBuf},
+    time::Instant,
+};
+use tar::EntryType;
+use uuid::Uuid;
+
+/// Extension of gzip-compressed tar backups.
+pub const TAR_GZ_EXTENSION: &str = "tar.gz";
+
+/// Shapes `rbak dir` can write a backup in, selected with `--format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupFormat {
+    /// A plain `_bak` directory tree
+    #[default]
+    Dir,
+    /// A gzip-compressed tar archive (`_bak.tar.gz`)
+    TarGz,
+}
+
+/// Archives the directory `src` next to where its `_bak` directory `bak_dir`
+/// would go, returning the archive (or, with `per_dir`, the directory holding
+/// the archives) and what went into it.
+///
+/// With `per_dir`, each top-level subdirectory gets its own
+/// `<source>_<subdir>_bak.tar.gz` and the top-level files go into
+/// `<source>_root_bak.tar.gz`, so one subdirectory can be restored alone.
+pub fn archive_directory(
+    src: &Path,
+    bak_dir: &Path,
+    opts: &BackupOptions,
+    per_dir: bool,
+) -> Result<(PathBuf, BackupStats)> {
+    let parent = bak_dir.parent().unwrap_or(Path::new(""));
+    let bak_name = bak_dir.file_name().unwrap_or_default().to_string_lossy();
+    if !per_dir {
+        let archive = parent.join(format!("{bak_name}.{TAR_GZ_EXTENSION}"));
+        let stats = create_archive(src, src, &archive, opts, false)?;
+        return Ok((archive, stats));
+    }
+
+    let source = bak_name.strip_suffix("_bak").unwrap_or(&bak_name);
+    let archive_for = |part: &str| parent.join(format!("{source}_{part}_bak.{TAR_GZ_EXTENSION}"));
+    let mut subdirs = Vec::new();
+    let mut skipped = 0;
+    for entry in fs::read_dir(src).with_context(|| format!("reading {}", src.display()))? {
+        let entry = entry.context("reading directory entry")?;
+        let path = entry.path();
+        if !entry.file_type().context("getting file type")?.is_dir() {
+            continue;
+        }
+        if opts.is_excluded(&path, Path::new(&entry.file_name()), true)? {
+            skipped += 1;
+        } else {
+            subdirs.push(entry.file_name());
+        }
+    }
+    subdirs.sort();
+    if subdirs.iter().any(|name| name == "root") {
+        bail!(
+            "{} has a subdirectory named `root`, whose archive would clash with the one for top-level files",
+            src.display()
+        );
+    }
+
+    let mut stats = create_archive(src, src, &archive_for("root"), opts, true)?;
+    stats.skipped += skipped;
+    for name in subdirs {
+        let archive = archive_for(&name.to_string_lossy());
+        let sub = create_archive(&src.join(&name), src, &archive, opts, false)?;
+        stats.files += sub.files;
+        stats.dirs += sub.dirs;
+        stats.bytes += sub.bytes;
+        stats.skipped += sub.skipped;
+        stats.duration += sub.duration;
+    }
+    let dest = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    Ok((dest.to_path_buf(), stats))
+}
+
+type ArchiveBuilder = tar::Builder<GzEncoder<BufWriter<File>>>;
+
+/// Writes the tree under `src` into a `.tar.gz` at `archive`, with entry
+/// paths relative to `src`, honouring the filters in `opts`.
+///
+/// `filter_root` is the source root that exclude patterns are relative to.
+/// With `top_level_only`, subdirectories of `src` are left out. With
+/// `opts.write_manifest`, a [`Manifest`] of the archived files is appended as
+/// the last entry (`--embed-manifest`).
+pub fn create_archive(
+    src: &Path,
+    filter_root: &Path,
+    archive: &Path,
+    opts: &BackupOptions,
+    top_level_only: bool,
+) -> Result<BackupStats> {
+    let started = Instant::now();
+    let builder = if opts.dry_run.would_create(archive) {
+        if let Some(parent) = archive.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context("creating backup directory")?;
+        }
+        let file =
+            File::create(archive).with_context(|| format!("creating {}", archive.display()))?;
+        let encoder = GzEncoder::new(BufWriter::new(file), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        builder.follow_symlinks(false);
+        Some(builder)
+    } else {
+        None
+    };
+    let mut writer = ArchiveWriter {
+        manifest: (builder.is_some() && opts.write_manifest).then(Manifest::for_new_backup),
+        builder,
+        opts,
+        filter_root,
+        stats: BackupStats::default(),
+    };
+    writer.append_tree(src, Path::new(""), top_level_only)?;
+
+    if let Some(mut builder) = writer.builder {
+        if let Some(manifest) = &writer.manifest {
+            let json = serde_json::to_vec_pretty(manifest).context("serializing manifest")?;
+            let mut header = tar::Header::new_gnu();
+            header.set_size(json.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, MANIFEST_NAME, json.as_slice())
+                .context("embedding manifest")?;
+        }
+        builder
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .with_context(|| format!("finishing {}", archive.display()))?;
+    }
+    let mut stats = writer.stats;
+    stats.duration = started.elapsed();
+    Ok(stats)
+}
+
+/// State threaded through writing one archive; `builder` is `None` on a dry run.
+struct ArchiveWriter<'a> {
+    builder: Option<ArchiveBuilder>,
+    manifest: Option<Manifest>,
+    opts: &'a BackupOptions,
+    filter_root: &'a Path,
+    stats: BackupStats,
+}
+
+impl ArchiveWriter<'_> {
+    /// Appends the entries of `dir`, named under `name`, to the archive.
+    fn append_tree(&mut self, dir: &Path, name: &Path, top_level_only: bool) -> Result<()> {
+        self.stats.dirs += 1;
+        let mut entries = fs::read_dir(dir)
+            .with_context(|| format!("reading {}", dir.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("reading directory entry")?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let file_type = entry.file_type().context("getting file type")?;
+            let path = entry.path();
+            let rel = path.strip_prefix(self.filter_root).unwrap_or(&path);
+            let entry_name = name.join(entry.file_name());
+            if file_type.is_dir() && top_level_only {
+                continue;
+            }
+            if self.opts.is_excluded(&path, rel, file_type.is_dir())? {
+                self.stats.skipped += 1;
+                continue;
+            }
+            if file_type.is_dir() {
+                if let Some(builder) = &mut self.builder {
+                    builder
+                        .append_dir(&entry_name, &path)
+                        .with_context(|| format!("archiving {}", path.display()))?;
+                }
+                self.append_tree(&path, &entry_name, false)?;
+            } else if file_type.is_file() {
+                let meta = entry.metadata().context("reading file metadata")?;
+                if let Some(builder) = &mut self.builder {
+                    builder
+                        .append_path_with_name(&path, &entry_name)
+                        .with_context(|| format!("archiving {}", path.display()))?;
+                }
+                if let Some(manifest) = &mut self.manifest {
+                    let entry = ManifestEntry::with_hash(&meta, hash_file(&path)?);
+                    manifest.entries.insert(manifest_key(&entry_name), entry);
+                }
+                self.stats.files += 1;
+                self.stats.bytes += meta.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options for [`extract_archive`].
+#[derive(Debug, Default, Clone)]
+pub struct ExtractOptions {
+    /// Extract entries even if they (or their link targets) resolve outside
+    /// the destination. Only for archives from a trusted source.
+    pub allow_escape: bool,
+    pub dry_run: DryRunMode,
+    /// Translates the owners recorded in the archive.
+    pub owners: Option<OwnerMap>,
+}
+
+/// Extracts a `.tar.gz` archive into `dest`, returning counts of the
+/// directories, files and bytes it held.
+///
+/// Unless `allow_escape` is set, the whole archive is checked before anything
+/// is written: absolute paths, `..` components that climb out of `dest`, and
+/// symlinks or hard links pointing outside it all abort the extraction.
+pub fn extract_archive(archive: &Path, dest: &Path, opts: ExtractOptions) -> Result<BackupStats> {
+    if !opts.allow_escape {
+        let mut tar = open_archive(archive)?;
+        for entry in tar.entries().context("reading archive")? {
+            let entry = entry.context("reading archive entry")?;
+            let path = entry.path().context("reading entry path")?;
+            let link = entry.link_name().context("reading link target")?;
+            check_entry(&path, entry.header().entry_type(), link.as_deref())?;
+        }
+    }
+
+    if opts.dry_run.would_create(dest) {
+        fs::create_dir_all(dest).context("creating restore directory")?;
+    }
+    let mut stats = BackupStats {
+        dirs: 1,
+        ..Default::default()
+    };
+    let mut tar = open_archive(archive)?;
+    for entry in tar.entries().context("reading archive")? {
+        let mut entry = entry.context("reading archive entry")?;
+        let path = entry.path().context("reading entry path")?.into_owned();
+        // An embedded manifest is rbak's bookkeeping, as in directory backups.
+        if path == Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        if entry.header().entry_type().is_dir() {
+            stats.dirs += 1;
+        } else {
+            stats.files += 1;
+            stats.bytes += entry.size();
+        }
+        if !opts
+            .dry_run
+            .would_copy(&archive.join(&path), &dest.join(&path))
+        {
+            continue;
+        }
+        if opts.allow_escape {
+            // `dest.join` keeps absolute paths and `..` as they are.
+            let target = dest.join(&path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).context("creating parent directory")?;
+            }
+            entry
+                .unpack(&target)
+                .with_context(|| format!("extracting {}", path.display()))?;
+        } else {
+            // `unpack_in` also refuses to write through symlinks extracted earlier.
+            entry
+                .unpack_in(dest)
+                .with_context(|| format!("extracting {}", path.display()))?;
+        }
+        if let Some(owners) = &opts.owners {
+            let header = entry.header();
+            let id = |id: std::io::Result<u64>| -> Result<u32> {
+                Ok(u32::try_from(id.context("reading entry owner")?)?)
+            };
+            let (uid, gid) = (id(header.uid())?, id(header.gid())?);
+            owners.apply_ids(uid, gid, &dest.join(&path))?;
+        }
+    }
+    Ok(stats)
+}
+
+/// Reads the backup ID from the manifest embedded in `archive`, or `None` if
+/// it has none. The manifest is the last entry, so this reads the whole archive.
+pub fn archive_id(archive: &Path) -> Result<Option<Uuid>> {
+    let mut tar = open_archive(archive)?;
+    for entry in tar.entries().context("reading archive")? {
+        let entry = entry.context("reading archive entry")?;
+        if entry.path().context("reading entry path")? == Path::new(MANIFEST_NAME) {
+            return Manifest::parse_id(entry).context("parsing embedded manifest");
+        }
+    }
+    Ok(None)
+}
+
+/// Opens a `.tar.gz` for reading its entries in order.
+pub fn open_archive(path: &Path) -> Result<tar::Archive<GzDecoder<BufReader<File>>>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    Ok(tar::Archive::new(GzDecoder::new(BufReader::new(file))))
+}
+
+/// Rejects an archive entry that would land outside the extraction root.
+///
+/// Symlink targets are resolved from the entry's own directory; hard link
+/// targets, like entry paths, from the archive root.
+pub fn check_entry(path: &Path, kind: EntryType, link: Option<&Path>) -> Result<()> {
+    let Some(resolved) = normalize_within_root(Path::new(""), path) else {
+        bail!(
+            "archive entry {} escapes the destination (use --allow-escape to extract anyway)",
+            path.display()
+        );
+    };
+    let base = match kind {
+        EntryType::Symlink => resolved.parent().map(Path::to_path_buf).unwrap_or_default(),
+        EntryType::Link => PathBuf::new(),
+        _ => return Ok(()),
+    };
+    if let Some(target) = link {
+        if normalize_within_root(&base, target).is_none() {
+            bail!(
+                "archive link {} -> {} escapes the destination (use --allow-escape to extract anyway)",
+                path.display(),
+                target.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Lexically applies `rel` to `base` (both relative to a root), returning
+/// `None` if the result is absolute or climbs above the root.
+pub(crate) fn normalize_within_root(base: &Path, rel: &Path) -> Option<PathBuf> {
+    let mut parts: Vec<_> = base.components().collect();
+    for component in rel.components() {
+        match component {
+            Component::Normal(_) => parts.push(component),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(parts.iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use tempfile::TempDir;
+
+    /// Writes a `.tar.gz` whose entries bypass the `tar` crate's own path checks.
+    fn write_raw_archive(path: &Path, entries: &[(&str, EntryType, &[u8], &str)]) {
+        let encoder = GzEncoder::new(File::create(path).unwrap(), Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, kind, data, link) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            if !link.is_empty() {
+                header.set_link_name(link).unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_one_archive_per_dir() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("docs/drafts")).unwrap();
+        fs::create_dir_all(src.join("photos")).unwrap();
+        fs::create_dir_all(src.join("target")).unwrap();
+        fs::write(src.join("top.txt"), b"top").unwrap();
+        fs::write(src.join("docs/drafts/plan.md"), b"plan").unwrap();
+        fs::write(src.join("photos/cat.jpg"), b"meow").unwrap();
+        fs::write(src.join("target/app"), b"bin").unwrap();
+
+        let out = tmp.path().join("out");
+        let opts = BackupOptions {
+            exclude: vec![crate::ExcludePattern::new("target/").unwrap()],
+            ..Default::default()
+        };
+        let (dest, stats) = archive_directory(&src, &out.join("data_bak"), &opts, true).unwrap();
+        assert_eq!(dest, out);
+        assert_eq!((stats.files, stats.bytes, stats.skipped), (3, 11, 1));
+
+        let mut names: Vec<_> = fs::read_dir(&out)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "data_docs_bak.tar.gz",
+                "data_photos_bak.tar.gz",
+                "data_root_bak.tar.gz"
+            ]
+        );
+
+        // A single subdirectory restores on its own.
+        let docs = tmp.path().join("docs");
+        let opts = ExtractOptions::default();
+        extract_archive(&out.join("data_docs_bak.tar.gz"), &docs, opts.clone()).unwrap();
+        assert_eq!(fs::read(docs.join("drafts/plan.md")).unwrap(), b"plan");
+        let root = tmp.path().join("root");
+        let stats = extract_archive(&out.join("data_root_bak.tar.gz"), &root, opts).unwrap();
+        assert_eq!(stats.files, 1);
+        assert_eq!(fs::read(root.join("top.txt")).unwrap(), b"top");
+    }
+
+    #[test]
+    fn test_extract_rejects_parent_dir_escape() {
+        let tmp = TempDir::new().unwrap();
+        let archive = tmp.path().join("evil.tar.gz");
+        write_raw_archive(
+            &archive,
+            &[
+                ("ok.txt", EntryType::Regular, b"fine", ""),
+                ("../escaped.txt", EntryType::Regular, b"pwned", ""),
+            ],
+        );
+
+        let dest = tmp.path().join("out");
+        let err = extract_archive(&archive, &dest, ExtractOptions::default()).unwrap_err();
+
+        assert!(err.to_string().contains("escapes the destination"));
+        assert!(!tmp.path().join("escaped.txt").exists());
+        // Validation runs before extraction, so nothing was written at all.
+        assert!(!dest.join("ok.txt").exists());
+    }
+
+    #[test]
+    fn test_extract_rejects_symlink_escape() {
+        let tmp = TempDir::new().unwrap();
+        let archive = tmp.path().join("evil.tar.gz");
+        write_raw_archive(
+            &archive,
+            &[("sub/link", EntryType::Symlink, b"", "../../etc")],
+        );
+
+        let err = extract_archive(&archive, &tmp.path().join("out"), ExtractOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("escapes the destination"));
+    }
+
+    #[test]
+    fn test_check_entry_allows_links_inside_root() {
+        let inside = check_entry(
+            Path::new("a/b/link"),
+            EntryType::Symlink,
+            Some(Path::new("../c")),
+        );
+        assert!(inside.is_ok());
+        assert!(check_entry(Path::new("/etc/passwd"), EntryType::Regular, None).is_err());
+        assert!(check_entry(Path::new("x"), EntryType::Link, Some(Path::new("../y"))).is_err());
+    }
+
+    #[test]
+    fn test_extract_with_allow_escape() {
+        let tmp = TempDir::new().unwrap();
+        let archive = tmp.path().join("trusted.tar.gz");
+        write_raw_archive(
+            &archive,
+            &[("../sibling.txt", EntryType::Regular, b"ok", "")],
+        );
+
+        let dest = tmp.path().join("out");
+        let opts = ExtractOptions {
+            allow_escape: true,
+            ..Default::default()
+        };
+        assert_eq!(extract_archive(&archive, &dest, opts).unwrap().files, 1);
+        assert_eq!(fs::read(tmp.path().join("sibling.txt")).unwrap(), b"ok");
+    }
+}
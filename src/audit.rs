@@ -0,0 +1,333 @@
+use crate::{
+    history::{parse_time_arg, time_before, History, HistoryFilter, RunRecord, RunStatus},
+    parse_duration,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use globset::Glob;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+/// Minimum number of earlier runs of a source before one can be called slow.
+const MIN_RUNS_FOR_BASELINE: usize = 3;
+
+/// Arguments of `rbak audit`.
+#[derive(Debug, clap::Args)]
+pub struct AuditArgs {
+    /// Only runs started at or after TIME (`7d`, `2026-10-01`, RFC 3339)
+    #[arg(long, value_name = "TIME", value_parser = parse_time_arg)]
+    since: Option<DateTime<Utc>>,
+    /// Only runs started at or before TIME (default: now)
+    #[arg(long, value_name = "TIME", value_parser = parse_time_arg)]
+    until: Option<DateTime<Utc>>,
+    /// Only runs whose source path matches GLOB
+    #[arg(long, value_name = "GLOB")]
+    source: Option<String>,
+    /// Output layout
+    #[arg(long, value_name = "FORMAT", default_value = "table")]
+    format: AuditFormat,
+    /// Flag runs taking more than FACTOR times the source's median duration
+    #[arg(long, value_name = "FACTOR", default_value_t = 3.0)]
+    slow_factor: f64,
+    /// Flag runs with more than N errors
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_errors: u64,
+    /// Flag sources without a successful run within DURATION
+    #[arg(long, value_name = "DURATION", default_value = "7d", value_parser = parse_stale_after)]
+    stale_after: Duration,
+}
+
+/// Clap value parser for `--stale-after`: a duration that does not reach back
+/// further than chrono can represent.
+fn parse_stale_after(s: &str) -> Result<Duration, String> {
+    let limit = parse_duration(s)?;
+    match time_before(Utc::now(), limit) {
+        Some(_) => Ok(limit),
+        None => Err(format!("`{s}` reaches back too far")),
+    }
+}
+
+/// Output layouts for `rbak audit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AuditFormat {
+    Table,
+    Json,
+}
+
+/// Something in the history worth a closer look.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Anomaly {
+    /// A run took much longer than is usual for its source.
+    Slow {
+        run_id: i64,
+        duration_ms: u64,
+        median_ms: u64,
+    },
+    /// A run reported more errors than allowed.
+    Errors { run_id: i64, errors: u64 },
+    /// A source has not been backed up successfully for too long.
+    Stale {
+        source: String,
+        last_success: Option<DateTime<Utc>>,
+    },
+}
+
+impl Anomaly {
+    fn run_id(&self) -> Option<i64> {
+        match self {
+            Anomaly::Slow { run_id, .. } | Anomaly::Errors { run_id, .. } => Some(*run_id),
+            Anomaly::Stale { .. } => None,
+        }
+    }
+}
+
+/// Runs in the audited range plus the anomalies found in them.
+#[derive(Debug, Serialize)]
+pub struct AuditReport {
+    pub runs: Vec<RunRecord>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// Thresholds deciding what counts as an anomaly.
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    pub slow_factor: f64,
+    pub max_errors: u64,
+    pub stale_after: Duration,
+}
+
+/// Builds an audit report.
+///
+/// `runs` are the runs in the audited range; `all_runs` is the full history of
+/// the same sources, used for duration baselines and staleness.
+pub fn audit(
+    runs: Vec<RunRecord>,
+    all_runs: &[RunRecord],
+    thresholds: &Thresholds,
+    now: DateTime<Utc>,
+) -> AuditReport {
+    let mut durations: BTreeMap<&str, Vec<u64>> = BTreeMap::new();
+    let mut last_success: BTreeMap<&str, Option<DateTime<Utc>>> = BTreeMap::new();
+    for run in all_runs {
+        let last = last_success.entry(&run.source).or_default();
+        if run.status == RunStatus::Ok {
+            durations
+                .entry(&run.source)
+                .or_default()
+                .push(run.duration_ms);
+            *last = (*last).max(Some(run.started_at));
+        }
+    }
+
+    let mut anomalies = Vec::new();
+    for run in &runs {
+        let baseline = durations
+            .get(run.source.as_str())
+            .filter(|d| d.len() >= MIN_RUNS_FOR_BASELINE);
+        if let Some(median_ms) = baseline.map(|d| median(d)) {
+            if run.duration_ms as f64 > median_ms as f64 * thresholds.slow_factor {
+                anomalies.push(Anomaly::Slow {
+                    run_id: run.id,
+                    duration_ms: run.duration_ms,
+                    median_ms,
+                });
+            }
+        }
+        if run.errors > thresholds.max_errors {
+            anomalies.push(Anomaly::Errors {
+                run_id: run.id,
+                errors: run.errors,
+            });
+        }
+    }
+
+    // `--stale-after` is checked when parsed; anything older still counts as recent.
+    let stale_before = time_before(now, thresholds.stale_after).unwrap_or(DateTime::<Utc>::MIN_UTC);
+    for (source, last) in last_success {
+        if last.is_none_or(|t| t < stale_before) {
+            anomalies.push(Anomaly::Stale {
+                source: source.to_string(),
+                last_success: last,
+            });
+        }
+    }
+
+    AuditReport { runs, anomalies }
+}
+
+fn median(values: &[u64]) -> u64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted[sorted.len() / 2]
+}
+
+impl AuditReport {
+    /// Renders the report as an aligned table followed by the anomalies.
+    pub fn to_table(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:>5}  {:<20}  {:<7}  {:>7}  {:>12}  {:>10}  {:>6}  SOURCE",
+            "ID", "STARTED (UTC)", "STATUS", "FILES", "BYTES", "DURATION", "ERRORS"
+        );
+        for run in &self.runs {
+            let flagged = self.anomalies.iter().any(|a| a.run_id() == Some(run.id));
+            let _ = writeln!(
+                out,
+                "{:>5}  {:<20}  {:<7}  {:>7}  {:>12}  {:>9.1}s  {:>6}  {}{}",
+                run.id,
+                run.started_at.format("%Y-%m-%d %H:%M:%S"),
+                run.status.as_str(),
+                run.files,
+                run.bytes,
+                run.duration_ms as f64 / 1000.0,
+                run.errors,
+                run.source,
+                if flagged { "  !" } else { "" }
+            );
+        }
+
+        if !self.anomalies.is_empty() {
+            let _ = writeln!(out, "\nAnomalies:");
+            for anomaly in &self.anomalies {
+                let _ = match anomaly {
+                    Anomaly::Slow {
+                        run_id,
+                        duration_ms,
+                        median_ms,
+                    } => writeln!(
+                        out,
+                        "  run {run_id} took {duration_ms} ms (median {median_ms} ms)"
+                    ),
+                    Anomaly::Errors { run_id, errors } => {
+                        writeln!(out, "  run {run_id} had {errors} errors")
+                    }
+                    Anomaly::Stale {
+                        source,
+                        last_success: Some(t),
+                    } => writeln!(
+                        out,
+                        "  {source} last backed up successfully {}",
+                        t.format("%Y-%m-%d %H:%M:%S")
+                    ),
+                    Anomaly::Stale {
+                        source,
+                        last_success: None,
+                    } => writeln!(out, "  {source} has never been backed up successfully"),
+                };
+            }
+        }
+        out
+    }
+}
+
+/// Runs `rbak audit` against the history database.
+pub fn run(args: &AuditArgs, history: &History) -> Result<()> {
+    let source = args
+        .source
+        .as_deref()
+        .map(|glob| Glob::new(glob).map(|g| g.compile_matcher()))
+        .transpose()
+        .context("invalid --source pattern")?;
+    let all_runs = history.query(&HistoryFilter {
+        source: source.clone(),
+        ..Default::default()
+    })?;
+    let in_range = history.query(&HistoryFilter {
+        since: args.since,
+        until: args.until,
+        source,
+    })?;
+
+    let thresholds = Thresholds {
+        slow_factor: args.slow_factor,
+        max_errors: args.max_errors,
+        stale_after: args.stale_after,
+    };
+    let report = audit(in_range, &all_runs, &thresholds, Utc::now());
+    match args.format {
+        AuditFormat::Table => print!("{}", report.to_table()),
+        AuditFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::tests::run;
+    use chrono::TimeZone;
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            slow_factor: 3.0,
+            max_errors: 0,
+            stale_after: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+
+    fn with_id(id: i64, record: RunRecord) -> RunRecord {
+        RunRecord { id, ..record }
+    }
+
+    #[test]
+    fn test_audit_flags_anomalies() {
+        let runs = vec![
+            with_id(1, run("/data", 1, RunStatus::Ok, 100)),
+            with_id(2, run("/data", 2, RunStatus::Ok, 110)),
+            with_id(3, run("/data", 3, RunStatus::Ok, 90)),
+            with_id(4, run("/data", 10, RunStatus::Ok, 1000)),
+            with_id(5, run("/data", 11, RunStatus::Failed, 5)),
+            with_id(6, run("/old", 1, RunStatus::Ok, 100)),
+        ];
+        let now = Utc.with_ymd_and_hms(2026, 10, 12, 0, 0, 0).unwrap();
+        let report = audit(runs[3..5].to_vec(), &runs, &thresholds(), now);
+
+        assert_eq!(
+            report.anomalies,
+            [
+                Anomaly::Slow {
+                    run_id: 4,
+                    duration_ms: 1000,
+                    median_ms: 110,
+                },
+                Anomaly::Errors {
+                    run_id: 5,
+                    errors: 1,
+                },
+                Anomaly::Stale {
+                    source: "/old".to_string(),
+                    last_success: Some(runs[5].started_at),
+                },
+            ]
+        );
+        let table = report.to_table();
+        assert!(table.contains("/data  !"));
+        assert!(table.contains("/old last backed up successfully 2026-10-01 03:00:00"));
+    }
+
+    #[test]
+    fn test_audit_needs_baseline_for_slow_runs() {
+        let runs = vec![
+            with_id(1, run("/data", 10, RunStatus::Ok, 100)),
+            with_id(2, run("/data", 11, RunStatus::Ok, 5000)),
+        ];
+        let now = Utc.with_ymd_and_hms(2026, 10, 12, 0, 0, 0).unwrap();
+        let report = audit(runs.clone(), &runs, &thresholds(), now);
+        assert!(report.anomalies.is_empty());
+    }
+
+    #[test]
+    fn test_stale_after_must_be_representable() {
+        assert_eq!(
+            parse_stale_after("7d").unwrap(),
+            Duration::from_secs(7 * 24 * 60 * 60)
+        );
+        assert_eq!(
+            parse_stale_after("99999999999d").unwrap_err(),
+            "`99999999999d` reaches back too far"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{manifest, CopyObserver};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Hash algorithms for `--hash-file`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgo {
+    /// Checked with `sha256sum -c`
+    #[default]
+    Sha256,
+    /// Checked with `b3sum -c`
+    Blake3,
+    /// Checked with `md5sum -c`
+    Md5,
+}
+
+impl HashAlgo {
+    /// Computes the hex-encoded hash of a file.
+    pub fn hash_file(self, path: &Path) -> Result<String> {
+        match self {
+            HashAlgo::Sha256 => digest_file::<Sha256>(path),
+            HashAlgo::Blake3 => manifest::hash_file(path),
+            HashAlgo::Md5 => digest_file::<Md5>(path),
+        }
+    }
+}
+
+fn digest_file<D: Digest>(path: &Path) -> Result<String> {
+    let mut hasher = D::new();
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("opening {}", path.display()))?);
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .with_context(|| format!("hashing {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    }))
+}
+
+/// A text file of `hash  path` lines, as written by `sha256sum` and friends.
+pub struct HashFile {
+    algo: HashAlgo,
+    path: PathBuf,
+    out: BufWriter<Box<dyn Write>>,
+}
+
+impl HashFile {
+    /// Creates (or truncates) the hash file at `path`; `-` streams the lines
+    /// to standard output instead.
+    pub fn create(path: &Path, algo: HashAlgo) -> Result<Self> {
+        let out: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(path).with_context(|| format!("creating {}", path.display()))?)
+        };
+        Ok(Self {
+            algo,
+            path: path.to_path_buf(),
+            out: BufWriter::new(out),
+        })
+    }
+}
+
+impl CopyObserver for HashFile {
+    /// Hashes `src` and records it under `name`.
+    fn file_copied(&mut self, src: &Path, name: &str) -> Result<()> {
+        let hash = self.algo.hash_file(src)?;
+        self.out
+            .write_all(format_line(&hash, name).as_bytes())
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.out
+            .flush()
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+}
+
+/// Formats one checksum line: the hash, a space, the mode indicator (a space,
+/// meaning text mode, which is all `b3sum` writes) and the name.
+///
+/// Like coreutils and `b3sum`, names containing a backslash, newline or
+/// carriage return are escaped and the line is prefixed with `\` so `-c`
+/// (`--check`) reads them back correctly.
+pub fn format_line(hash: &str, name: &str) -> String {
+    if name.contains(['\\', '\n', '\r']) {
+        let escaped = name
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        format!("\\{hash}  {escaped}\n")
+    } else {
+        format!("{hash}  {name}\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory_with, BackupOptions};
+    use anyhow::{bail, ensure};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Parses a line the way `b3sum --check` and `sha256sum -c` do.
+    fn parse_line(line: &str) -> Result<(String, String)> {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (hash, rest) = line
+            .split_once(' ')
+            .with_context(|| format!("malformed checksum line `{line}`"))?;
+        ensure!(
+            !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_hexdigit()),
+            "invalid hash `{hash}`"
+        );
+        // `*` marks binary mode in coreutils output.
+        let name = rest
+            .strip_prefix([' ', '*'])
+            .with_context(|| format!("missing mode indicator in `{line}`"))?;
+        if !escaped {
+            return Ok((hash.to_string(), name.to_string()));
+        }
+
+        let mut unescaped = String::with_capacity(name.len());
+        let mut chars = name.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+            unescaped.push(match chars.next() {
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                other => bail!("invalid escape `\\{}` in `{name}`", other.unwrap_or(' ')),
+            });
+        }
+        Ok((hash.to_string(), unescaped))
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("hello.txt");
+        fs::write(&file, b"hello").unwrap();
+
+        assert_eq!(
+            HashAlgo::Sha256.hash_file(&file).unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(
+            HashAlgo::Md5.hash_file(&file).unwrap(),
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+        assert_eq!(format_line("ab", "a\\b"), "\\ab  a\\\\b\n");
+    }
+
+    #[test]
+    fn test_b3sum_lines_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("hello.txt");
+        fs::write(&file, b"hello").unwrap();
+        let hash = HashAlgo::Blake3.hash_file(&file).unwrap();
+        // Byte for byte what `b3sum hello.txt` prints.
+        assert_eq!(
+            format_line(&hash, "hello.txt"),
+            "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f  hello.txt\n"
+        );
+
+        for name in [
+            "hello.txt",
+            "dir/with spaces.txt",
+            "back\\slash",
+            "new\nline",
+            "carriage\rreturn",
+            "ünïcödé/*star",
+        ] {
+            let line = format_line(&hash, name);
+            assert_eq!(line.matches('\n').count(), 1, "{line:?}");
+            assert_eq!(parse_line(&line).unwrap(), (hash.clone(), name.to_string()));
+        }
+
+        assert_eq!(
+            parse_line("abc1 *binary.bin").unwrap(),
+            ("abc1".to_string(), "binary.bin".to_string())
+        );
+        assert!(parse_line("not-a-hash  file").is_err());
+        assert!(parse_line("\\abc1  bad\\escape").is_err());
+    }
+
+    #[test]
+    fn test_backup_directory_writes_relative_hash_file() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir_all(src_dir.join("sub")).unwrap();
+        fs::write(src_dir.join("sub/hello.txt"), b"hello").unwrap();
+
+        let hash_file = tmp.path().join("hashes.md5");
+        let opts = BackupOptions {
+            hash_file: Some(hash_file.clone()),
+            hash_algo: HashAlgo::Md5,
+            ..Default::default()
+        };
+        backup_directory_with(&src_dir, &tmp.path().join("src_bak"), &opts).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&hash_file).unwrap(),
+            "5d41402abc4b2a76b9719d911017c592  sub/hello.txt\n"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use crate::manifest::{hash_file, mtime_ns};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    fs::{self, Metadata},
+    path::Path,
+};
+
+/// File name of the cache database inside the `--checksum-store` directory.
+const STORE_NAME: &str = "checksums.sqlite";
+
+/// BLAKE3 hashes cached between runs, so unchanged files are not re-read.
+///
+/// An entry is reused only while the file's path, inode, size and mtime all
+/// match what was recorded when it was hashed.
+pub struct ChecksumStore {
+    conn: Connection,
+}
+
+impl ChecksumStore {
+    /// Opens (creating if needed) the store in `dir`.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let path = dir.join(STORE_NAME);
+        let conn = Connection::open(&path)
+            .with_context(|| format!("opening checksum store {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS checksums (
+                path     TEXT PRIMARY KEY,
+                inode    INTEGER NOT NULL,
+                size     INTEGER NOT NULL,
+                mtime_ns INTEGER NOT NULL,
+                blake3   TEXT NOT NULL
+            );
+            BEGIN;",
+        )
+        .context("initialising checksum store")?;
+        Ok(Self { conn })
+    }
+
+    /// Returns the BLAKE3 hash of `path`, from the cache if `meta` still
+    /// matches the cached entry, otherwise by hashing the file and caching it.
+    pub fn blake3(&self, path: &Path, meta: &Metadata) -> Result<String> {
+        let key = std::path::absolute(path)
+            .context("resolving absolute path")?
+            .to_string_lossy()
+            .into_owned();
+        let (inode, size, mtime) = (inode(meta), meta.len(), mtime_ns(meta));
+        let cached: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT blake3 FROM checksums
+                 WHERE path = ?1 AND inode = ?2 AND size = ?3 AND mtime_ns = ?4",
+                params![key, inode, size, mtime],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("reading checksum store")?;
+        if let Some(hash) = cached {
+            return Ok(hash);
+        }
+
+        let hash = hash_file(path)?;
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO checksums (path, inode, size, mtime_ns, blake3)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![key, inode, size, mtime, hash],
+            )
+            .context("updating checksum store")?;
+        Ok(hash)
+    }
+
+    /// Writes this run's new entries to disk.
+    pub fn commit(self) -> Result<()> {
+        self.conn
+            .execute_batch("COMMIT;")
+            .context("saving checksum store")
+    }
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(meta)
+}
+
+/// Inodes are not exposed on stable Rust elsewhere; size and mtime still apply.
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, FileTimes};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_checksum_store_reuses_and_invalidates() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("data.bin");
+        fs::write(&file, b"first").unwrap();
+        let original = fs::metadata(&file).unwrap();
+
+        let store = ChecksumStore::open(&tmp.path().join("store")).unwrap();
+        let first = store.blake3(&file, &original).unwrap();
+        assert_eq!(first, blake3::hash(b"first").to_hex().as_str());
+        store.commit().unwrap();
+
+        // Same size and mtime: the stale cached hash proves the file was not re-read.
+        fs::write(&file, b"again").unwrap();
+        let times = FileTimes::new().set_modified(original.modified().unwrap());
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_times(times)
+            .unwrap();
+        let store = ChecksumStore::open(&tmp.path().join("store")).unwrap();
+        let meta = fs::metadata(&file).unwrap();
+        assert_eq!(store.blake3(&file, &meta).unwrap(), first);
+
+        fs::write(&file, b"changed size").unwrap();
+        let meta = fs::metadata(&file).unwrap();
+        assert_eq!(
+            store.blake3(&file, &meta).unwrap(),
+            blake3::hash(b"changed size").to_hex().as_str()
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+/// Whether a directory backup renames entries whose names differ only in case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaseFolding {
+    /// Only if the destination's filesystem turns out to fold case.
+    #[default]
+    Detect,
+    /// Always, as if the destination folded case.
+    Assume,
+}
+
+/// Reports whether the filesystem holding the directory `path` tells apart
+/// names differing only in case.
+///
+/// Creates a probe file in `path`, looks it up under an upper-cased name and
+/// removes it again. Each directory is probed once per process.
+pub fn is_case_sensitive(path: &Path) -> Result<bool> {
+    static PROBED: Mutex<BTreeMap<PathBuf, bool>> = Mutex::new(BTreeMap::new());
+    let path = fs::canonicalize(path).with_context(|| format!("resolving {}", path.display()))?;
+    let mut probed = PROBED.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(&sensitive) = probed.get(&path) {
+        return Ok(sensitive);
+    }
+    let probe = Probe(path.join(format!(".rbak-case-probe-{}", std::process::id())));
+    fs::write(&probe.0, b"").with_context(|| format!("probing {}", path.display()))?;
+    let upper = path.join(
+        probe
+            .0
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_uppercase(),
+    );
+    let sensitive = !upper.exists();
+    probe.remove()?;
+    probed.insert(path, sensitive);
+    Ok(sensitive)
+}
+
+/// A probe file, removed when dropped if [`Probe::remove`] was not reached.
+struct Probe(PathBuf);
+
+impl Probe {
+    fn remove(mut self) -> Result<()> {
+        let path = std::mem::take(&mut self.0);
+        fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if !self.0.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// Names already used in one destination directory, compared ignoring case.
+#[derive(Debug, Default)]
+pub struct CaseFoldedNames {
+    taken: HashSet<String>,
+}
+
+impl CaseFoldedNames {
+    /// Claims `name`, returning it unchanged if no name differing only in
+    /// case was claimed before, or else a renamed `stem~N.ext` that is free.
+    pub fn claim(&mut self, name: &OsStr) -> OsString {
+        if self.taken.insert(fold(name)) {
+            return name.to_os_string();
+        }
+        let path = Path::new(name);
+        let stem = path.file_stem().unwrap_or(name).to_string_lossy();
+        let ext = path
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        (1..)
+            .map(|n| OsString::from(format!("{stem}~{n}{ext}")))
+            .find(|candidate| self.taken.insert(fold(candidate)))
+            .unwrap()
+    }
+}
+
+fn fold(name: &OsStr) -> String {
+    name.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory_with, manifest::Manifest, verify::verify_backup, BackupOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_is_case_sensitive_cleans_up() {
+        let tmp = TempDir::new().unwrap();
+        let sensitive = is_case_sensitive(tmp.path()).unwrap();
+        // Linux temp directories are case-sensitive unless explicitly set up otherwise.
+        #[cfg(target_os = "linux")]
+        assert!(sensitive);
+        #[cfg(not(target_os = "linux"))]
+        let _ = sensitive;
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_case_folded_names_disambiguate() {
+        let mut names = CaseFoldedNames::default();
+        assert_eq!(names.claim(OsStr::new("foo.txt")), "foo.txt");
+        assert_eq!(names.claim(OsStr::new("FOO.txt")), "FOO~1.txt");
+        assert_eq!(names.claim(OsStr::new("Foo.TXT")), "Foo~2.TXT");
+        assert_eq!(names.claim(OsStr::new("foo~1.TXT")), "foo~1~1.TXT");
+        assert_eq!(names.claim(OsStr::new("Makefile")), "Makefile");
+        assert_eq!(names.claim(OsStr::new("makefile")), "makefile~1");
+    }
+
+    #[test]
+    fn test_case_collisions_are_recorded_under_written_names() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("foo.txt"), b"lower").unwrap();
+        fs::write(src.join("FOO.txt"), b"upper").unwrap();
+        let dst = tmp.path().join("data_bak");
+        let hashes = tmp.path().join("hashes");
+        let opts = BackupOptions {
+            case_folding: CaseFolding::Assume,
+            write_manifest: true,
+            incremental: true,
+            delete: true,
+            hash_file: Some(hashes.clone()),
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src, &dst, &opts).unwrap();
+        assert_eq!(stats.files, 2);
+
+        // Entries are sorted, so the upper-case name keeps its spelling.
+        assert_eq!(fs::read(dst.join("FOO.txt")).unwrap(), b"upper");
+        assert_eq!(fs::read(dst.join("foo~1.txt")).unwrap(), b"lower");
+        let manifest = Manifest::read(&dst).unwrap();
+        let keys: Vec<_> = manifest.entries.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["FOO.txt", "foo~1.txt"]);
+        let renamed = &manifest.entries["foo~1.txt"];
+        assert_eq!(renamed.source_path("foo~1.txt"), "foo.txt");
+        assert_eq!(manifest.entries["FOO.txt"].source, None);
+        let listed = fs::read_to_string(&hashes).unwrap();
+        assert!(listed.contains("  foo.txt\n") && !listed.contains("foo~1"));
+
+        let report = verify_backup(&dst, None).unwrap().unwrap();
+        assert_eq!((report.checked, report.is_intact()), (2, true));
+
+        // Rerunning finds both unchanged and deletes neither.
+        let stats = backup_directory_with(&src, &dst, &opts).unwrap();
+        assert_eq!((stats.files, stats.unchanged, stats.deleted), (0, 2, 0));
+        assert_eq!(Manifest::read(&dst).unwrap().entries, manifest.entries);
+    }
+}
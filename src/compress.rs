@@ -0,0 +1,123 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+};
+
+/// Compression algorithms selectable with `--compress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Brotli (`.br`), well suited to text that will be served over HTTP
+    Brotli,
+}
+
+impl Compression {
+    /// Builds the compressor for this algorithm, validating `level` if given.
+    pub fn compressor(self, level: Option<u32>) -> Result<Box<dyn Compressor>> {
+        match self {
+            Compression::Brotli => Ok(Box::new(BrotliCompressor::new(
+                level.unwrap_or(BrotliCompressor::DEFAULT_QUALITY),
+            )?)),
+        }
+    }
+}
+
+/// Streams file contents into a compressed backup.
+pub trait Compressor: Debug {
+    /// Extension appended to compressed backups, without the leading dot.
+    fn extension(&self) -> &'static str;
+
+    /// Compresses everything from `reader` into `writer`, returning the bytes read.
+    fn compress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64>;
+
+    /// Decompresses a stream written by [`Compressor::compress`], returning
+    /// the bytes written.
+    fn decompress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64>;
+}
+
+/// Brotli compressor with a quality level between 0 (fastest) and 11 (smallest).
+#[derive(Debug, Clone, Copy)]
+pub struct BrotliCompressor {
+    quality: u32,
+}
+
+impl BrotliCompressor {
+    pub const DEFAULT_QUALITY: u32 = 11;
+    pub const MAX_QUALITY: u32 = 11;
+    /// Base-2 log of the sliding window size; 22 is the format's recommended default.
+    const LG_WINDOW: u32 = 22;
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    pub fn new(quality: u32) -> Result<Self> {
+        if quality > Self::MAX_QUALITY {
+            bail!(
+                "brotli quality must be between 0 and {}, got {quality}",
+                Self::MAX_QUALITY
+            );
+        }
+        Ok(Self { quality })
+    }
+}
+
+impl Compressor for BrotliCompressor {
+    fn extension(&self) -> &'static str {
+        "br"
+    }
+
+    fn compress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64> {
+        let mut encoder =
+            brotli::CompressorWriter::new(writer, Self::BUFFER_SIZE, self.quality, Self::LG_WINDOW);
+        let read = io::copy(reader, &mut encoder).context("brotli compression")?;
+        // Finishing the stream happens in `into_inner`; flush what it wrote.
+        encoder
+            .into_inner()
+            .flush()
+            .context("flushing brotli stream")?;
+        Ok(read)
+    }
+
+    fn decompress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64> {
+        let mut decoder = brotli::Decompressor::new(reader, Self::BUFFER_SIZE);
+        io::copy(&mut decoder, writer).context("brotli decompression")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brotli_round_trip() {
+        let input = b"hello hello hello hello brotli".repeat(100);
+        let mut compressed = Vec::new();
+        let read = BrotliCompressor::new(5)
+            .unwrap()
+            .compress(&mut input.as_slice(), &mut compressed)
+            .unwrap();
+
+        assert_eq!(read, input.len() as u64);
+        assert!(compressed.len() < input.len());
+
+        let mut output = Vec::new();
+        brotli::Decompressor::new(compressed.as_slice(), 4096)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, input);
+
+        let mut decompressed = Vec::new();
+        BrotliCompressor::new(5)
+            .unwrap()
+            .decompress(&mut compressed.as_slice(), &mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, input);
+    }
+
+    #[test]
+    fn test_brotli_rejects_out_of_range_quality() {
+        assert!(BrotliCompressor::new(12).is_err());
+        assert!(Compression::Brotli.compressor(Some(0)).is_ok());
+    }
+}
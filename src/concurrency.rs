@@ -0,0 +1,246 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// How the copy stage of a directory backup runs, selected with
+/// `--concurrency-model`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConcurrencyModel {
+    /// A pool of OS threads, one per CPU
+    #[default]
+    Threads,
+    /// Async file IO on a tokio runtime (needs the `async-io` feature)
+    Async,
+}
+
+impl ConcurrencyModel {
+    /// Creates the backend for this model.
+    pub fn backend(self) -> Result<Box<dyn CopyBackend>> {
+        match self {
+            ConcurrencyModel::Threads => Ok(Box::new(ThreadPool::new())),
+            #[cfg(feature = "async-io")]
+            ConcurrencyModel::Async => Ok(Box::new(AsyncIo::new()?)),
+            #[cfg(not(feature = "async-io"))]
+            ConcurrencyModel::Async => {
+                anyhow::bail!(
+                    "--concurrency-model async needs rbak built with the `async-io` feature"
+                )
+            }
+        }
+    }
+}
+
+/// A file whose contents the walk wants copied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyJob {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+}
+
+/// Copies file contents for a directory backup.
+///
+/// The walk decides what gets copied where; a backend only decides how the
+/// copies of one batch overlap.
+pub trait CopyBackend {
+    /// Copies every job, returning the result of each (the bytes copied), in
+    /// order. A failed copy does not stop the others.
+    fn copy_files(&self, jobs: &[CopyJob]) -> Vec<Result<u64>>;
+}
+
+/// Splits a batch across scoped OS threads.
+#[derive(Debug)]
+pub struct ThreadPool {
+    workers: usize,
+}
+
+impl ThreadPool {
+    pub fn new() -> Self {
+        Self {
+            workers: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        }
+    }
+}
+
+impl CopyBackend for ThreadPool {
+    fn copy_files(&self, jobs: &[CopyJob]) -> Vec<Result<u64>> {
+        if jobs.len() <= 1 {
+            return jobs.iter().map(|job| copy(&job.src, &job.dst)).collect();
+        }
+        let chunk = jobs.len().div_ceil(self.workers);
+        thread::scope(|scope| {
+            let workers: Vec<_> = jobs
+                .chunks(chunk)
+                .map(|chunk| {
+                    let worker = scope.spawn(|| {
+                        chunk
+                            .iter()
+                            .map(|job| copy(&job.src, &job.dst))
+                            .collect::<Vec<_>>()
+                    });
+                    (chunk.len(), worker)
+                })
+                .collect();
+            let mut results = Vec::with_capacity(jobs.len());
+            for (len, worker) in workers {
+                match worker.join() {
+                    Ok(copied) => results.extend(copied),
+                    Err(_) => {
+                        results.extend((0..len).map(|_| Err(anyhow!("copy worker panicked"))))
+                    }
+                }
+            }
+            results
+        })
+    }
+}
+
+fn copy(src: &Path, dst: &Path) -> Result<u64> {
+    fs::copy(src, dst).with_context(|| format!("copying {}", src.display()))
+}
+
+/// Starts every copy of a batch as a task on a tokio runtime.
+#[cfg(feature = "async-io")]
+#[derive(Debug)]
+pub struct AsyncIo {
+    runtime: tokio::runtime::Runtime,
+}
+
+#[cfg(feature = "async-io")]
+impl AsyncIo {
+    pub fn new() -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .context("starting async runtime")?;
+        Ok(Self { runtime })
+    }
+}
+
+#[cfg(feature = "async-io")]
+impl CopyBackend for AsyncIo {
+    fn copy_files(&self, jobs: &[CopyJob]) -> Vec<Result<u64>> {
+        self.runtime.block_on(async {
+            let tasks: Vec<_> = jobs
+                .iter()
+                .cloned()
+                .map(|job| {
+                    tokio::spawn(async move {
+                        tokio::fs::copy(&job.src, &job.dst)
+                            .await
+                            .with_context(|| format!("copying {}", job.src.display()))
+                    })
+                })
+                .collect();
+            let mut results = Vec::with_capacity(jobs.len());
+            for task in tasks {
+                results.push(task.await.context("copy task failed").and_then(|r| r));
+            }
+            results
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backup_directory_with,
+        manifest::{Manifest, MANIFEST_NAME},
+        BackupOptions,
+    };
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    /// Every file under `root`, by relative path.
+    fn snapshot(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let rel = path.strip_prefix(root).unwrap().to_path_buf();
+                    files.insert(rel, fs::read(&path).unwrap());
+                }
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_backends_produce_identical_backups() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        for dir in ["a/b/c", "d", "empty"] {
+            fs::create_dir_all(src.join(dir)).unwrap();
+        }
+        for i in 0..40 {
+            let dir = ["", "a", "a/b", "a/b/c", "d"][i % 5];
+            let contents = format!("file {i} ").repeat(i * 50);
+            fs::write(src.join(dir).join(format!("f{i}.txt")), contents).unwrap();
+        }
+
+        let mut results = Vec::new();
+        let models = ConcurrencyModel::value_variants()
+            .iter()
+            .filter(|m| cfg!(feature = "async-io") || **m != ConcurrencyModel::Async);
+        for model in models {
+            let dst = tmp.path().join(format!("{model:?}"));
+            let opts = BackupOptions {
+                concurrency: *model,
+                preserve_times: true,
+                write_manifest: true,
+                ..Default::default()
+            };
+            let mut stats = backup_directory_with(&src, &dst, &opts).unwrap();
+            stats.duration = Default::default();
+            let mut files = snapshot(&dst);
+            // Each backup gets its own ID; everything else must match.
+            let mut manifest = Manifest::read(&dst).unwrap();
+            assert!(manifest.id.take().is_some());
+            files.insert(MANIFEST_NAME.into(), Vec::new());
+            results.push((stats, files, manifest));
+        }
+
+        let (stats, files, _) = &results[0];
+        assert_eq!((stats.files, stats.dirs), (40, 6));
+        assert_eq!(files.len(), 41);
+        assert!(results.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[test]
+    fn test_failed_copies_are_not_recorded() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+
+        let models = ConcurrencyModel::value_variants()
+            .iter()
+            .filter(|m| cfg!(feature = "async-io") || **m != ConcurrencyModel::Async);
+        for model in models {
+            // A directory in the way makes the queued copy of b.txt fail.
+            let dst = tmp.path().join(format!("{model:?}"));
+            fs::create_dir_all(dst.join("b.txt")).unwrap();
+            let hashes = tmp.path().join(format!("{model:?}.b3"));
+            let opts = BackupOptions {
+                concurrency: *model,
+                hash_file: Some(hashes.clone()),
+                ..Default::default()
+            };
+            assert!(backup_directory_with(&src, &dst, &opts).is_err());
+            let listed = fs::read_to_string(&hashes).unwrap();
+            assert!(!listed.contains("b.txt"), "{model:?}: {listed}");
+        }
+    }
+}
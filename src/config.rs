@@ -0,0 +1,374 @@
+use crate::{archive::BackupFormat, compress::Compression, concurrency::ConcurrencyModel};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Backup settings read from `[package.metadata.rbak]` in a `Cargo.toml`:
+///
+/// ```toml
+/// [package.metadata.rbak]
+/// exclude = ["target/", "*.tmp"]
+/// keep = 5
+/// keep-daily = 7
+/// ```
+///
+/// or from the top level of a config file given with `--config`. Each key
+/// is named after its `rbak dir` flag, which takes precedence.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Directory to put the backup in.
+    pub dest: Option<PathBuf>,
+    /// Exclude patterns, added to any given with `--exclude`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Content-type patterns, added to any given with `--exclude-mime`.
+    #[serde(default)]
+    pub exclude_mime: Vec<String>,
+    #[serde(default)]
+    pub exclude_zero_byte: bool,
+    pub compress: Option<Compression>,
+    pub compress_level: Option<u32>,
+    pub format: Option<BackupFormat>,
+    #[serde(default)]
+    pub manifest: bool,
+    #[serde(default)]
+    pub incremental: bool,
+    #[serde(default)]
+    pub merge_manifests: bool,
+    #[serde(default)]
+    pub delete: bool,
+    #[serde(default)]
+    pub detect_renames: bool,
+    pub store: Option<PathBuf>,
+    pub checksum_store: Option<PathBuf>,
+    pub concurrency_model: Option<ConcurrencyModel>,
+    /// Number of timestamped backups to keep.
+    pub keep: Option<usize>,
+    /// GFS retention.
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CargoToml {
+    package: Option<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    metadata: Option<Metadata>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    rbak: Option<Config>,
+}
+
+impl Config {
+    /// Reads `[package.metadata.rbak]` from a `Cargo.toml`.
+    ///
+    /// A manifest without that table yields the default config.
+    pub fn from_cargo_toml(path: &Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let cargo: CargoToml =
+            toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        let config = cargo
+            .package
+            .and_then(|p| p.metadata)
+            .and_then(|m| m.rbak)
+            .unwrap_or_default();
+        Ok(config.relative_to(path))
+    }
+
+    /// Reads a standalone config file, whose keys are those of
+    /// `[package.metadata.rbak]` at the top level.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let config: Self =
+            toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(config.relative_to(path))
+    }
+
+    /// Resolves relative directories against the directory of the file the
+    /// config was read from, so it means the same from any working directory.
+    fn relative_to(self, path: &Path) -> Self {
+        let base = path.parent().unwrap_or(Path::new(""));
+        let resolve = |dir: Option<PathBuf>| dir.map(|dir| base.join(dir));
+        Self {
+            dest: resolve(self.dest),
+            store: resolve(self.store),
+            checksum_store: resolve(self.checksum_store),
+            ..self
+        }
+    }
+
+    /// Renders the config as a commented TOML file for `rbak config generate`.
+    ///
+    /// Unset settings are included commented out, so the file documents every key.
+    pub fn to_commented_toml(&self) -> String {
+        let mut out = String::from(
+            "# rbak configuration, generated by `rbak config generate`.\n\
+             # Load it with `rbak dir <path> --config <file>`, or paste the settings under\n\
+             # [package.metadata.rbak] in Cargo.toml and use `--cargo-config`.\n\
+             # Flags given on the command line take precedence over these settings.\n",
+        );
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| string(&p.to_string_lossy()));
+        let flag = |set: bool| set.then(|| "true".to_string());
+        let count = |n: Option<usize>| n.map(|n| n.to_string());
+        let keys = [
+            (
+                "dest",
+                path(&self.dest),
+                "\"backups\"",
+                "Directory to put the backup in.",
+            ),
+            (
+                "exclude",
+                Some(list(&self.exclude)),
+                "",
+                "Gitignore-style patterns of entries to skip, added to any given with --exclude.",
+            ),
+            (
+                "exclude-mime",
+                Some(list(&self.exclude_mime)),
+                "",
+                "Content types of files to skip, added to any given with --exclude-mime.",
+            ),
+            (
+                "exclude-zero-byte",
+                flag(self.exclude_zero_byte),
+                "true",
+                "Skip empty (0-byte) files.",
+            ),
+            (
+                "compress",
+                self.compress.map(value_name),
+                "\"brotli\"",
+                "Compress backed-up files with this algorithm.",
+            ),
+            (
+                "compress-level",
+                self.compress_level.map(|n| n.to_string()),
+                "11",
+                "Compression level (brotli: 0-11).",
+            ),
+            (
+                "format",
+                self.format.map(value_name),
+                "\"tar-gz\"",
+                "Write the backup as a `_bak` directory (\"dir\") or an archive (\"tar-gz\").",
+            ),
+            (
+                "manifest",
+                flag(self.manifest),
+                "true",
+                "Write a manifest of backed-up files into the backup.",
+            ),
+            (
+                "incremental",
+                flag(self.incremental),
+                "true",
+                "Only copy files changed since the manifest in the destination.",
+            ),
+            (
+                "merge-manifests",
+                flag(self.merge_manifests),
+                "true",
+                "Merge each run's entries into the existing manifest (needs incremental).",
+            ),
+            (
+                "delete",
+                flag(self.delete),
+                "true",
+                "Delete backed-up files whose source no longer exists (needs incremental).",
+            ),
+            (
+                "detect-renames",
+                flag(self.detect_renames),
+                "true",
+                "Move the copy of a renamed file instead of copying it again (needs incremental).",
+            ),
+            (
+                "store",
+                path(&self.store),
+                "\"objects\"",
+                "Put file contents in a deduplicating object store in this directory.",
+            ),
+            (
+                "checksum-store",
+                path(&self.checksum_store),
+                "\"checksums\"",
+                "Cache manifest checksums in this directory.",
+            ),
+            (
+                "concurrency-model",
+                self.concurrency_model.map(value_name),
+                "\"async\"",
+                "Copy files with a pool of threads (\"threads\") or with async IO (\"async\").",
+            ),
+            (
+                "keep",
+                count(self.keep),
+                "5",
+                "Number of timestamped backups to keep.",
+            ),
+            (
+                "keep-daily",
+                count(self.keep_daily),
+                "5",
+                "Keep the newest backup of each of the last N days.",
+            ),
+            (
+                "keep-weekly",
+                count(self.keep_weekly),
+                "5",
+                "Keep the newest backup of each of the last N ISO weeks.",
+            ),
+            (
+                "keep-monthly",
+                count(self.keep_monthly),
+                "5",
+                "Keep the newest backup of each of the last N months.",
+            ),
+        ];
+        for (key, value, example, comment) in keys {
+            out.push_str(&format!("\n# {comment}\n"));
+            match value {
+                Some(value) => out.push_str(&format!("{key} = {value}\n")),
+                None => out.push_str(&format!("# {key} = {example}\n")),
+            }
+        }
+        out
+    }
+}
+
+fn string(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+fn list(items: &[String]) -> String {
+    let items: Vec<_> = items.iter().map(|item| string(item)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// Quotes the command-line spelling of `value`, which is also its config spelling.
+fn value_name(value: impl ValueEnum) -> String {
+    let value = value.to_possible_value().expect("no skipped variants");
+    string(value.get_name())
+}
+
+/// Finds the nearest `Cargo.toml` in `start` or one of its ancestors.
+pub fn find_cargo_toml(start: &Path) -> Option<PathBuf> {
+    let start = fs::canonicalize(start).ok()?;
+    start
+        .ancestors()
+        .map(|dir| dir.join("Cargo.toml"))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_config_from_cargo_toml() {
+        let tmp = TempDir::new().unwrap();
+        let manifest = tmp.path().join("Cargo.toml");
+        fs::write(
+            &manifest,
+            r#"
+[package]
+name = "demo"
+
+[package.metadata.rbak]
+exclude = ["target/", "*.tmp"]
+keep = 5
+keep-monthly = 12
+"#,
+        )
+        .unwrap();
+
+        let config = Config::from_cargo_toml(&manifest).unwrap();
+        assert_eq!(config.exclude, ["target/", "*.tmp"]);
+        assert_eq!(config.keep, Some(5));
+        assert_eq!(config.keep_monthly, Some(12));
+
+        let nested = tmp.path().join("src/bin");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(
+            find_cargo_toml(&nested).unwrap(),
+            fs::canonicalize(&manifest).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_config_paths_are_relative_to_the_file() {
+        let tmp = TempDir::new().unwrap();
+        let project = tmp.path().join("project");
+        fs::create_dir(&project).unwrap();
+        let path = project.join("rbak.toml");
+        fs::write(
+            &path,
+            "dest = \"../backups\"\nstore = \"objects\"\nchecksum-store = \"/srv/checksums\"\n",
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.dest, Some(project.join("../backups")));
+        assert_eq!(config.store, Some(project.join("objects")));
+        assert_eq!(config.checksum_store, Some(PathBuf::from("/srv/checksums")));
+
+        let manifest = project.join("Cargo.toml");
+        fs::write(&manifest, "[package.metadata.rbak]\ndest = \"backups\"\n").unwrap();
+        let config = Config::from_cargo_toml(&manifest).unwrap();
+        assert_eq!(config.dest, Some(project.join("backups")));
+    }
+
+    #[test]
+    fn test_config_missing_table_is_default() {
+        let tmp = TempDir::new().unwrap();
+        let manifest = tmp.path().join("Cargo.toml");
+        fs::write(&manifest, "[package]\nname = \"demo\"\n").unwrap();
+        assert_eq!(
+            Config::from_cargo_toml(&manifest).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn test_generated_config_round_trips() {
+        let config = Config {
+            dest: Some(PathBuf::from("/srv/backups")),
+            exclude: vec!["target/".to_string(), "say \"hi\".txt".to_string()],
+            compress: Some(Compression::Brotli),
+            compress_level: Some(9),
+            format: Some(BackupFormat::TarGz),
+            incremental: true,
+            concurrency_model: Some(ConcurrencyModel::Async),
+            keep: Some(10),
+            keep_weekly: Some(4),
+            ..Default::default()
+        };
+        let text = config.to_commented_toml();
+        assert!(text.contains("# Number of timestamped backups to keep"));
+        assert!(text.contains("# keep-daily = 5\n"));
+        assert!(text.contains("\nformat = \"tar-gz\"\n"));
+        assert!(text.contains("\n# manifest = true\n"));
+
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        fs::write(&path, &text).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap(), config);
+
+        fs::write(&path, Config::default().to_commented_toml()).unwrap();
+        assert_eq!(Config::from_file(&path).unwrap(), Config::default());
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::Path;
+
+/// A gitignore-style exclusion pattern.
+///
+/// - `*.tmp` (no `/`) matches an entry's name at any depth.
+/// - `docs/*.pdf` (contains `/`) matches the path relative to the source root.
+/// - `target/` (trailing `/`) only matches directories.
+#[derive(Debug, Clone)]
+pub struct ExcludePattern {
+    matcher: GlobMatcher,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl ExcludePattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(rest) => (rest, true),
+            None => (pattern, false),
+        };
+        let anchored = pattern.contains('/');
+        let glob = GlobBuilder::new(pattern.trim_start_matches('/'))
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid exclude pattern `{pattern}`"))?;
+        Ok(Self {
+            matcher: glob.compile_matcher(),
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// Tests an entry, given its path relative to the source root.
+    pub fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.matcher.is_match(rel)
+        } else {
+            rel.file_name()
+                .is_some_and(|name| self.matcher.is_match(name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_pattern_forms() {
+        let tmp = ExcludePattern::new("*.tmp").unwrap();
+        assert!(tmp.matches(Path::new("a/b/c.tmp"), false));
+        assert!(!tmp.matches(Path::new("a/b/c.txt"), false));
+
+        let target = ExcludePattern::new("target/").unwrap();
+        assert!(target.matches(Path::new("crates/x/target"), true));
+        assert!(!target.matches(Path::new("target"), false));
+
+        let anchored = ExcludePattern::new("docs/*.pdf").unwrap();
+        assert!(anchored.matches(Path::new("docs/a.pdf"), false));
+        assert!(!anchored.matches(Path::new("docs/sub/a.pdf"), false));
+        assert!(!anchored.matches(Path::new("other/docs/a.pdf"), false));
+    }
+}
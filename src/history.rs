@@ -0,0 +1,621 @@
+use crate::{parse_duration, summary::format_size};
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, SecondsFormat, TimeDelta, Utc};
+use clap::ValueEnum;
+use globset::{Glob, GlobMatcher};
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ValueRef},
+    Connection, Row,
+};
+use serde::Serialize;
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Arguments of `rbak history`.
+#[derive(Debug, clap::Args)]
+pub struct HistoryArgs {
+    /// Only runs that backed up PATH
+    path: Option<PathBuf>,
+    /// Only runs started at or after TIME (`7d`, `2026-10-01`, RFC 3339)
+    #[arg(long, value_name = "TIME", value_parser = parse_time_arg)]
+    since: Option<DateTime<Utc>>,
+    /// Only runs started at or before TIME (default: now)
+    #[arg(long, value_name = "TIME", value_parser = parse_time_arg)]
+    until: Option<DateTime<Utc>>,
+    /// Only the N most recent matching runs
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+    /// Only runs whose source path matches GLOB
+    #[arg(long, value_name = "GLOB")]
+    source: Option<String>,
+    /// Output layout
+    #[arg(long, value_name = "FORMAT", default_value = "table")]
+    format: HistoryFormat,
+}
+
+/// Output layouts for `rbak history`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HistoryFormat {
+    /// Aligned columns for reading in a terminal
+    Table,
+    /// Comma-separated values with a header row, for spreadsheets
+    Csv,
+    /// A JSON array of run records
+    Json,
+}
+
+/// Outcome of a recorded backup run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    /// Finished and copied everything.
+    Ok,
+    /// Stopped early (e.g. at `--max-runtime`).
+    Partial,
+    /// Aborted with an error.
+    Failed,
+}
+
+impl RunStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunStatus::Ok => "ok",
+            RunStatus::Partial => "partial",
+            RunStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromSql for RunStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "ok" => Ok(RunStatus::Ok),
+            "partial" => Ok(RunStatus::Partial),
+            "failed" => Ok(RunStatus::Failed),
+            other => Err(FromSqlError::Other(
+                format!("unknown run status `{other}`").into(),
+            )),
+        }
+    }
+}
+
+/// One backup run as stored in the history database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RunRecord {
+    /// Assigned by the database; ignored by [`History::record`].
+    pub id: i64,
+    pub started_at: DateTime<Utc>,
+    /// `file` or `dir`.
+    pub kind: String,
+    pub source: String,
+    pub destination: String,
+    pub status: RunStatus,
+    pub files: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub duration_ms: u64,
+    /// Error message for failed runs.
+    pub error: Option<String>,
+    /// ID of the backup the run wrote, if it wrote a manifest. Incremental
+    /// runs into the same backup share it.
+    pub backup_id: Option<String>,
+}
+
+/// Narrows a history query; unset fields match everything.
+#[derive(Debug, Default, Clone)]
+pub struct HistoryFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Glob matched against the recorded source path.
+    pub source: Option<GlobMatcher>,
+}
+
+/// SQLite database recording every backup run.
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    /// Opens (creating if needed) the history database at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).context("creating history directory")?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening history database {}", path.display()))?;
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            CREATE TABLE IF NOT EXISTS backups (
+                id          TEXT PRIMARY KEY NOT NULL,
+                destination TEXT NOT NULL,
+                created_at  TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS runs (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                started_at  TEXT NOT NULL,
+                kind        TEXT NOT NULL,
+                source      TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                status      TEXT NOT NULL,
+                files       INTEGER NOT NULL,
+                bytes       INTEGER NOT NULL,
+                errors      INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                error       TEXT,
+                backup_id   TEXT REFERENCES backups (id)
+            );
+            CREATE INDEX IF NOT EXISTS runs_started_at ON runs (started_at);",
+        )
+        .context("initialising history database")?;
+        // Databases from before backups had IDs lack the column.
+        let has_backup_id: bool = conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('runs') WHERE name = 'backup_id'",
+                [],
+                |row| row.get(0),
+            )
+            .context("reading history schema")?;
+        if !has_backup_id {
+            conn.execute_batch(
+                "ALTER TABLE runs ADD COLUMN backup_id TEXT REFERENCES backups (id)",
+            )
+            .context("upgrading history database")?;
+        }
+        conn.execute_batch("CREATE INDEX IF NOT EXISTS runs_backup_id ON runs (backup_id)")
+            .context("initialising history database")?;
+        Ok(Self { conn })
+    }
+
+    /// Stores a run, returning its assigned id. A run that wrote a backup
+    /// also registers the backup, or updates where it was last written.
+    pub fn record(&self, run: &RunRecord) -> Result<i64> {
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .context("recording run in history")?;
+        if let Some(backup_id) = &run.backup_id {
+            tx.execute(
+                "INSERT INTO backups (id, destination, created_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET destination = excluded.destination",
+                params![backup_id, run.destination, format_time(run.started_at)],
+            )
+            .context("recording backup in history")?;
+        }
+        tx.execute(
+            "INSERT INTO runs (started_at, kind, source, destination, status,
+                               files, bytes, errors, duration_ms, error, backup_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                format_time(run.started_at),
+                run.kind,
+                run.source,
+                run.destination,
+                run.status.as_str(),
+                run.files,
+                run.bytes,
+                run.errors,
+                run.duration_ms,
+                run.error,
+                run.backup_id,
+            ],
+        )
+        .context("recording run in history")?;
+        let id = tx.last_insert_rowid();
+        tx.commit().context("recording run in history")?;
+        Ok(id)
+    }
+
+    /// Returns matching runs, oldest first.
+    pub fn query(&self, filter: &HistoryFilter) -> Result<Vec<RunRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, started_at, kind, source, destination, status,
+                    files, bytes, errors, duration_ms, error, backup_id
+             FROM runs
+             WHERE (?1 IS NULL OR started_at >= ?1) AND (?2 IS NULL OR started_at <= ?2)
+             ORDER BY started_at, id",
+        )?;
+        let rows = stmt.query_map(
+            params![filter.since.map(format_time), filter.until.map(format_time)],
+            read_row,
+        )?;
+
+        let mut runs = Vec::new();
+        for row in rows {
+            let run = row.context("reading history")?;
+            if filter
+                .source
+                .as_ref()
+                .is_none_or(|glob| glob.is_match(&run.source))
+            {
+                runs.push(run);
+            }
+        }
+        Ok(runs)
+    }
+}
+
+impl HistoryFormat {
+    /// Renders `runs` in this layout.
+    pub fn render(self, runs: &[RunRecord]) -> Result<String> {
+        match self {
+            HistoryFormat::Table => Ok(history_table(runs)),
+            HistoryFormat::Csv => Ok(history_csv(runs)),
+            HistoryFormat::Json => Ok(serde_json::to_string_pretty(runs)? + "\n"),
+        }
+    }
+}
+
+const TABLE_HEADER: [&str; 8] = [
+    "ID",
+    "Date",
+    "Source",
+    "Destination",
+    "Files",
+    "Size",
+    "Duration",
+    "Status",
+];
+
+fn history_table(runs: &[RunRecord]) -> String {
+    let rows: Vec<[String; 8]> = runs
+        .iter()
+        .map(|run| {
+            [
+                run.id.to_string(),
+                run.started_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                run.source.clone(),
+                run.destination.clone(),
+                run.files.to_string(),
+                format_size(run.bytes as f64),
+                format!("{:.1}s", run.duration_ms as f64 / 1000.0),
+                run.status.as_str().to_string(),
+            ]
+        })
+        .collect();
+    let mut widths = TABLE_HEADER.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let header = TABLE_HEADER.map(String::from);
+    for row in std::iter::once(&header).chain(&rows) {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(widths).enumerate() {
+            let sep = if i == 0 { "" } else { "  " };
+            // Counts (ID, Files, Size, Duration) line up on the right.
+            let _ = match i {
+                0 | 4..=6 => write!(line, "{sep}{cell:>width$}"),
+                _ => write!(line, "{sep}{cell:<width$}"),
+            };
+        }
+        let _ = writeln!(out, "{}", line.trim_end());
+    }
+    out
+}
+
+fn history_csv(runs: &[RunRecord]) -> String {
+    let mut out = String::from("id,date,source,destination,files,bytes,duration_ms,status\n");
+    for run in runs {
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            run.id,
+            run.started_at.format("%Y-%m-%d %H:%M:%S"),
+            csv_field(&run.source),
+            csv_field(&run.destination),
+            run.files,
+            run.bytes,
+            run.duration_ms,
+            run.status.as_str()
+        );
+    }
+    out
+}
+
+/// Quotes a field containing a comma, quote or line break, as RFC 4180 asks.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Picks the runs `rbak history` shows: those matching the filter and,
+/// if given, backing up `path`, cut down to the `limit` most recent.
+pub fn select(
+    history: &History,
+    filter: &HistoryFilter,
+    path: Option<&Path>,
+    limit: Option<usize>,
+) -> Result<Vec<RunRecord>> {
+    let mut runs = history.query(filter)?;
+    if let Some(path) = path {
+        // Sources are recorded canonicalized.
+        let source = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let source = source.display().to_string();
+        runs.retain(|run| run.source == source);
+    }
+    if let Some(limit) = limit {
+        runs.drain(..runs.len().saturating_sub(limit));
+    }
+    Ok(runs)
+}
+
+/// Runs `rbak history` against the history database.
+pub fn run(args: &HistoryArgs, history: &History) -> Result<()> {
+    let source = args
+        .source
+        .as_deref()
+        .map(|glob| Glob::new(glob).map(|g| g.compile_matcher()))
+        .transpose()
+        .context("invalid --source pattern")?;
+    let filter = HistoryFilter {
+        since: args.since,
+        until: args.until,
+        source,
+    };
+    let runs = select(history, &filter, args.path.as_deref(), args.limit)?;
+    print!("{}", args.format.render(&runs)?);
+    Ok(())
+}
+
+fn read_row(row: &Row) -> rusqlite::Result<RunRecord> {
+    Ok(RunRecord {
+        id: row.get(0)?,
+        started_at: row.get(1)?,
+        kind: row.get(2)?,
+        source: row.get(3)?,
+        destination: row.get(4)?,
+        status: row.get(5)?,
+        files: row.get(6)?,
+        bytes: row.get(7)?,
+        errors: row.get(8)?,
+        duration_ms: row.get(9)?,
+        error: row.get(10)?,
+        backup_id: row.get(11)?,
+    })
+}
+
+/// Fixed-width RFC 3339 so timestamps sort correctly as text.
+fn format_time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Parses a point in time for `--since`/`--until`.
+///
+/// Accepts `now`, a duration ago (`7d`, `12h`), a date (`2026-10-01`, taken as
+/// midnight UTC) or an RFC 3339 timestamp.
+pub fn parse_time(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    if s == "now" {
+        return Ok(now);
+    }
+    if let Ok(ago) = parse_duration(s) {
+        return time_before(now, ago).with_context(|| format!("`{s}` reaches back too far"));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .with_context(|| format!("invalid time `{s}` (try `now`, `7d` or `2026-10-01`)"))
+}
+
+/// The time `ago` before `now`, or `None` if that is earlier than chrono can
+/// represent.
+pub fn time_before(now: DateTime<Utc>, ago: Duration) -> Option<DateTime<Utc>> {
+    TimeDelta::from_std(ago)
+        .ok()
+        .and_then(|ago| now.checked_sub_signed(ago))
+}
+
+/// Clap value parser for time arguments, relative to the current time.
+pub fn parse_time_arg(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_time(s, Utc::now()).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use globset::Glob;
+
+    pub(crate) fn run(source: &str, day: u32, status: RunStatus, duration_ms: u64) -> RunRecord {
+        RunRecord {
+            id: 0,
+            started_at: Utc.with_ymd_and_hms(2026, 10, day, 3, 0, 0).unwrap(),
+            kind: "dir".to_string(),
+            source: source.to_string(),
+            destination: format!("{source}_bak"),
+            status,
+            files: 10,
+            bytes: 1000,
+            errors: u64::from(status == RunStatus::Failed),
+            duration_ms,
+            error: None,
+            backup_id: None,
+        }
+    }
+
+    #[test]
+    fn test_history_record_and_query() {
+        let history = History::open(Path::new(":memory:")).unwrap();
+        for (source, day) in [("/data", 1), ("/home", 2), ("/data", 3)] {
+            history
+                .record(&run(source, day, RunStatus::Ok, 100))
+                .unwrap();
+        }
+
+        let all = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].id, 1);
+        assert_eq!(
+            all[2],
+            RunRecord {
+                id: 3,
+                ..run("/data", 3, RunStatus::Ok, 100)
+            }
+        );
+
+        let filter = HistoryFilter {
+            since: Some(Utc.with_ymd_and_hms(2026, 10, 2, 0, 0, 0).unwrap()),
+            source: Some(Glob::new("/data*").unwrap().compile_matcher()),
+            ..Default::default()
+        };
+        let days: Vec<_> = history
+            .query(&filter)
+            .unwrap()
+            .iter()
+            .map(|r| r.started_at)
+            .collect();
+        assert_eq!(days, [Utc.with_ymd_and_hms(2026, 10, 3, 3, 0, 0).unwrap()]);
+    }
+
+    #[test]
+    fn test_history_formats() {
+        let history = History::open(Path::new(":memory:")).unwrap();
+        let mut failed = run("/srv/a,b", 4, RunStatus::Failed, 250);
+        failed.bytes = 2_500_000;
+        for record in [
+            run("/data", 1, RunStatus::Ok, 1200),
+            run("/home", 2, RunStatus::Ok, 100),
+            run("/data", 3, RunStatus::Partial, 900),
+            failed,
+        ] {
+            history.record(&record).unwrap();
+        }
+        let filter = HistoryFilter {
+            source: Some(Glob::new("/[ds]*").unwrap().compile_matcher()),
+            ..Default::default()
+        };
+        let runs = select(&history, &filter, None, Some(2)).unwrap();
+        assert_eq!(runs.iter().map(|r| r.id).collect::<Vec<_>>(), [3, 4]);
+
+        assert_eq!(
+            HistoryFormat::Table.render(&runs).unwrap(),
+            "\
+ID  Date                 Source    Destination   Files  Size  Duration  Status
+ 3  2026-10-03 03:00:00  /data     /data_bak        10  1 KB      0.9s  partial
+ 4  2026-10-04 03:00:00  /srv/a,b  /srv/a,b_bak     10  2 MB      0.2s  failed
+"
+        );
+        assert_eq!(
+            HistoryFormat::Csv.render(&runs).unwrap(),
+            "\
+id,date,source,destination,files,bytes,duration_ms,status
+3,2026-10-03 03:00:00,/data,/data_bak,10,1000,900,partial
+4,2026-10-04 03:00:00,\"/srv/a,b\",\"/srv/a,b_bak\",10,2500000,250,failed
+"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&HistoryFormat::Json.render(&runs).unwrap()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[1]["source"], "/srv/a,b");
+        assert_eq!(json[1]["status"], "failed");
+
+        let data = select(
+            &history,
+            &HistoryFilter::default(),
+            Some(Path::new("/data")),
+            None,
+        )
+        .unwrap();
+        assert_eq!(data.iter().map(|r| r.id).collect::<Vec<_>>(), [1, 3]);
+    }
+
+    #[test]
+    fn test_history_adds_backup_id_to_old_databases() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("history.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE runs (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT, started_at TEXT NOT NULL,
+                    kind TEXT NOT NULL, source TEXT NOT NULL, destination TEXT NOT NULL,
+                    status TEXT NOT NULL, files INTEGER NOT NULL, bytes INTEGER NOT NULL,
+                    errors INTEGER NOT NULL, duration_ms INTEGER NOT NULL, error TEXT
+                );
+                INSERT INTO runs VALUES
+                    (1, '2026-10-01T03:00:00.000Z', 'dir', '/data', '/data_bak', 'ok',
+                     10, 1000, 0, 100, NULL);",
+            )
+            .unwrap();
+
+        let history = History::open(&path).unwrap();
+        let record = RunRecord {
+            backup_id: Some("f47ac10b-58cc-4372-a567-0e02b2c3d479".to_string()),
+            ..run("/data", 2, RunStatus::Ok, 100)
+        };
+        history.record(&record).unwrap();
+        let runs = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(runs[0].backup_id, None);
+        assert_eq!(runs[1], RunRecord { id: 2, ..record });
+        drop(history);
+        assert!(History::open(&path).is_ok());
+    }
+
+    #[test]
+    fn test_history_keys_backups_by_id() {
+        let history = History::open(std::path::Path::new(":memory:")).unwrap();
+        let id = "f47ac10b-58cc-4372-a567-0e02b2c3d479".to_string();
+        let first = RunRecord {
+            backup_id: Some(id.clone()),
+            ..run("/data", 1, RunStatus::Ok, 100)
+        };
+        let moved = RunRecord {
+            destination: "/mnt/data_bak".to_string(),
+            ..first.clone()
+        };
+        history.record(&first).unwrap();
+        history.record(&moved).unwrap();
+
+        let backups: Vec<(String, String, String)> = history
+            .conn
+            .prepare("SELECT id, destination, created_at FROM backups")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            backups,
+            [(
+                id,
+                "/mnt/data_bak".to_string(),
+                "2026-10-01T03:00:00.000Z".to_string()
+            )]
+        );
+        // Runs may only point at registered backups.
+        let orphan = history.conn.execute(
+            "UPDATE runs SET backup_id = 'not-a-backup' WHERE id = 1",
+            [],
+        );
+        assert!(orphan.is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 15, 12, 0, 0).unwrap();
+        assert_eq!(parse_time("now", now).unwrap(), now);
+        assert_eq!(
+            parse_time("7d", now).unwrap(),
+            Utc.with_ymd_and_hms(2026, 10, 8, 12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_time("2026-10-01", now).unwrap(),
+            Utc.with_ymd_and_hms(2026, 10, 1, 0, 0, 0).unwrap()
+        );
+        assert!(parse_time("last tuesday", now).is_err());
+        let err = parse_time("99999999999d", now).unwrap_err();
+        assert_eq!(err.to_string(), "`99999999999d` reaches back too far");
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::{Context, Result};
+use std::{fs::File, io, path::Path};
+
+/// Block size `--inplace` compares files in.
+pub const INPLACE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Brings the existing file `dst` up to date with `src` by comparing them
+/// block by block and rewriting only the blocks that differ, then truncating
+/// `dst` to the length of `src`.
+///
+/// Returns the number of bytes written, so an appended log costs only its new
+/// tail.
+pub fn inplace_update(src: &Path, dst: &Path, block_size: usize) -> Result<u64> {
+    let source = File::open(src).with_context(|| format!("opening {}", src.display()))?;
+    let target = File::options()
+        .read(true)
+        .write(true)
+        .open(dst)
+        .with_context(|| format!("opening {}", dst.display()))?;
+    let mut src_block = vec![0; block_size];
+    let mut dst_block = vec![0; block_size];
+    let (mut offset, mut written) = (0, 0);
+    loop {
+        let n = read_at(&source, &mut src_block, offset)
+            .with_context(|| format!("reading {}", src.display()))?;
+        if n == 0 {
+            break;
+        }
+        let m = read_at(&target, &mut dst_block[..n], offset)
+            .with_context(|| format!("reading {}", dst.display()))?;
+        if m != n || src_block[..n] != dst_block[..n] {
+            write_at(&target, &src_block[..n], offset)
+                .with_context(|| format!("writing {}", dst.display()))?;
+            written += n as u64;
+        }
+        offset += n as u64;
+    }
+    target
+        .set_len(offset)
+        .with_context(|| format!("truncating {}", dst.display()))?;
+    Ok(written)
+}
+
+/// Fills as much of `buf` as the file holds from `offset` on.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match positioned_read(file, &mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match positioned_write(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn positioned_read(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn positioned_write(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn positioned_read(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn positioned_write(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory_with, backup_file, BackupOptions, ExcludePattern};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inplace_update_writes_changed_blocks_only() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("app.log");
+        let dst = tmp.path().join("app.bak");
+        fs::write(&src, b"aaaabbbbcccc").unwrap();
+        fs::write(&dst, b"aaaaXXXXcccc").unwrap();
+
+        // One changed block plus an appended partial block.
+        fs::write(&src, b"aaaabbbbccccdd").unwrap();
+        assert_eq!(inplace_update(&src, &dst, 4).unwrap(), 6);
+        assert_eq!(fs::read(&dst).unwrap(), b"aaaabbbbccccdd");
+
+        assert_eq!(inplace_update(&src, &dst, 4).unwrap(), 0);
+
+        fs::write(&src, b"aaaaz").unwrap();
+        assert_eq!(inplace_update(&src, &dst, 4).unwrap(), 1);
+        assert_eq!(fs::read(&dst).unwrap(), b"aaaaz");
+    }
+
+    #[test]
+    fn test_backup_file_inplace_falls_back_to_full_copy() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data.db");
+        let dst = tmp.path().join("data.bak");
+        fs::write(&src, vec![7; 3 * INPLACE_BLOCK_SIZE]).unwrap();
+        let opts = BackupOptions {
+            inplace: true,
+            ..Default::default()
+        };
+
+        let full = (3 * INPLACE_BLOCK_SIZE) as u64;
+        assert_eq!(backup_file(&src, &dst, &opts).unwrap(), full);
+        assert_eq!(backup_file(&src, &dst, &opts).unwrap(), 0);
+        assert_eq!(fs::read(&dst).unwrap(), fs::read(&src).unwrap());
+    }
+
+    #[test]
+    fn test_whole_file_patterns_bypass_inplace() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        let mut contents = vec![1; 4 * INPLACE_BLOCK_SIZE];
+        fs::write(src.join("app.log"), &contents).unwrap();
+        fs::write(src.join("app.db"), &contents).unwrap();
+        let bak = tmp.path().join("data_bak");
+        let opts = BackupOptions {
+            inplace: true,
+            whole_file: Some(vec![ExcludePattern::new("*.db").unwrap()]),
+            ..Default::default()
+        };
+        backup_directory_with(&src, &bak, &opts).unwrap();
+
+        contents[0] = 2;
+        fs::write(src.join("app.log"), &contents).unwrap();
+        fs::write(src.join("app.db"), &contents).unwrap();
+        let stats = backup_directory_with(&src, &bak, &opts).unwrap();
+        // One block of the log, all of the database.
+        assert_eq!(stats.bytes, (5 * INPLACE_BLOCK_SIZE) as u64);
+        assert_eq!(fs::read(bak.join("app.db")).unwrap(), contents);
+        assert_eq!(fs::read(bak.join("app.log")).unwrap(), contents);
+
+        assert!(opts.updates_inplace(Path::new("logs/app.log")));
+        assert!(!opts.updates_inplace(Path::new("db/app.db")));
+        let all = BackupOptions {
+            inplace: true,
+            whole_file: Some(Vec::new()),
+            ..Default::default()
+        };
+        assert!(!all.updates_inplace(Path::new("app.log")));
+    }
+}
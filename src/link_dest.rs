@@ -0,0 +1,178 @@
+use crate::{
+    manifest::{mtime_ns, Manifest, ManifestEntry},
+    DryRunMode,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, Metadata},
+    path::{Path, PathBuf},
+};
+
+/// A previous backup whose unchanged files are hard-linked into the new one
+/// instead of being copied (`--link-dest`, as in rsync).
+#[derive(Debug)]
+pub struct LinkDest {
+    root: PathBuf,
+    /// The previous backup's manifest, if it wrote one.
+    manifest: Option<Manifest>,
+}
+
+impl LinkDest {
+    /// Opens the previous backup in `root` after checking that files can be
+    /// hard-linked from it into `dst`.
+    pub fn open(root: &Path, dst: &Path, dry_run: DryRunMode) -> Result<Self> {
+        check_compatible(root, dst, dry_run)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            manifest: Manifest::load(root)?,
+        })
+    }
+
+    /// Returns the previous copy of `key` if the source file described by
+    /// `meta` is unchanged since, along with its manifest entry if recorded.
+    ///
+    /// `target` maps a backup path to where its copy is written, so compressed
+    /// backups find their `.br` files. Without a manifest a copy counts as
+    /// unchanged when its size and mtime match, like rsync's quick check; that
+    /// needs uncompressed copies made with `--preserve-times`.
+    pub fn unchanged(
+        &self,
+        key: &str,
+        meta: &Metadata,
+        target: impl Fn(&Path) -> PathBuf,
+    ) -> Option<(PathBuf, Option<ManifestEntry>)> {
+        let previous = target(&self.root.join(key));
+        match &self.manifest {
+            Some(manifest) => {
+                let entry = manifest.entries.get(key).filter(|e| e.matches(meta))?;
+                previous.is_file().then(|| (previous, Some(entry.clone())))
+            }
+            None => {
+                let copy = fs::metadata(&previous).ok()?;
+                (copy.is_file() && copy.len() == meta.len() && mtime_ns(&copy) == mtime_ns(meta))
+                    .then_some((previous, None))
+            }
+        }
+    }
+}
+
+/// Fails unless `link_dest` is a directory on the same filesystem as `dst`
+/// and that filesystem supports hard links.
+///
+/// Probes the nearest existing ancestor of `dst`, since it may not exist yet,
+/// by hard-linking a scratch file next to itself; a dry run skips the probe.
+pub fn check_compatible(link_dest: &Path, dst: &Path, dry_run: DryRunMode) -> Result<()> {
+    if !link_dest.is_dir() {
+        bail!("--link-dest {} is not a directory", link_dest.display());
+    }
+    let Some(existing) = dst.ancestors().find(|p| p.is_dir()) else {
+        bail!("no existing directory above {}", dst.display());
+    };
+    if !same_filesystem(link_dest, existing)? {
+        bail!(
+            "--link-dest {} is not on the same filesystem as {}; hard links cannot cross filesystems",
+            link_dest.display(),
+            dst.display()
+        );
+    }
+    if dry_run.is_dry_run() {
+        return Ok(());
+    }
+
+    let probe = existing.join(format!(".rbak-link-probe-{}", std::process::id()));
+    let linked = probe.with_extension("link");
+    fs::write(&probe, b"").with_context(|| format!("probing {}", existing.display()))?;
+    let result = fs::hard_link(&probe, &linked);
+    let _ = fs::remove_file(&linked);
+    fs::remove_file(&probe).with_context(|| format!("removing {}", probe.display()))?;
+    result.with_context(|| {
+        format!(
+            "the filesystem holding {} does not support hard links",
+            existing.display()
+        )
+    })
+}
+
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let dev = |p: &Path| {
+        fs::metadata(p)
+            .map(|m| m.dev())
+            .with_context(|| format!("reading metadata of {}", p.display()))
+    };
+    Ok(dev(a)? == dev(b)?)
+}
+
+/// Volume serial numbers are not exposed on stable Rust elsewhere; a link
+/// across volumes still fails when it is made.
+#[cfg(not(unix))]
+fn same_filesystem(_a: &Path, _b: &Path) -> Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory_with, BackupOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_link_dest_links_unchanged_files() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("same.txt"), b"unchanged").unwrap();
+        fs::write(src.join("sub/edit.txt"), b"before").unwrap();
+
+        let first = tmp.path().join("first");
+        let opts = BackupOptions {
+            write_manifest: true,
+            ..Default::default()
+        };
+        backup_directory_with(&src, &first, &opts).unwrap();
+
+        fs::write(src.join("sub/edit.txt"), b"after, longer").unwrap();
+        let second = tmp.path().join("second");
+        let opts = BackupOptions {
+            write_manifest: true,
+            link_dest: Some(first.clone()),
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src, &second, &opts).unwrap();
+        assert_eq!((stats.files, stats.unchanged), (1, 1));
+        assert_eq!(stats.bytes, 13);
+
+        assert_eq!(fs::read(second.join("same.txt")).unwrap(), b"unchanged");
+        assert_eq!(
+            fs::read(second.join("sub/edit.txt")).unwrap(),
+            b"after, longer"
+        );
+        assert_eq!(fs::read(first.join("sub/edit.txt")).unwrap(), b"before");
+        let manifest = Manifest::read(&second).unwrap();
+        assert_eq!(manifest.entries.len(), 2);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let ino = |p: PathBuf| fs::metadata(p).unwrap().ino();
+            assert_eq!(ino(first.join("same.txt")), ino(second.join("same.txt")));
+            assert_ne!(
+                ino(first.join("sub/edit.txt")),
+                ino(second.join("sub/edit.txt"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_compatible_rejects_missing_link_dest() {
+        let tmp = TempDir::new().unwrap();
+        let missing = tmp.path().join("missing");
+        let err =
+            check_compatible(&missing, &tmp.path().join("new"), DryRunMode::DryRun).unwrap_err();
+        assert!(err.to_string().contains("is not a directory"));
+        let dst = tmp.path().join("new/deeper");
+        assert!(check_compatible(tmp.path(), &dst, DryRunMode::Apply).is_ok());
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+    }
+}
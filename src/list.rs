@@ -0,0 +1,180 @@
+use crate::{
+    archive::{archive_id, TAR_GZ_EXTENSION},
+    config::Config,
+    manifest::Manifest,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+/// Arguments of `rbak list`.
+#[derive(Debug, clap::Args)]
+pub struct ListArgs {
+    /// Directories holding backups (default: the current directory)
+    dirs: Vec<PathBuf>,
+    /// Also list the backups in the `dest` of this config file (repeatable)
+    #[arg(long, value_name = "FILE")]
+    config: Vec<PathBuf>,
+    /// Print only the backup IDs, one per line
+    #[arg(long)]
+    id_only: bool,
+}
+
+/// A backup found by [`find_backups`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundBackup {
+    pub id: Uuid,
+    pub path: PathBuf,
+}
+
+/// Finds the backups with an ID in each of `dirs`: the directory itself and
+/// the backup directories and archives directly inside it, sorted by path.
+///
+/// Backups without a manifest have no ID and are left out. Archives are read
+/// to the end to reach their embedded manifest.
+pub fn find_backups(dirs: &[PathBuf]) -> Result<Vec<FoundBackup>> {
+    let mut found = Vec::new();
+    for dir in dirs {
+        let mut candidates = vec![dir.clone()];
+        for entry in fs::read_dir(dir).with_context(|| format!("listing {}", dir.display()))? {
+            candidates.push(entry.context("reading directory entry")?.path());
+        }
+        for path in candidates {
+            if let Some(id) = backup_id(&path)? {
+                found.push(FoundBackup { id, path });
+            }
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(found)
+}
+
+/// The ID of the backup directory or archive at `path`, if it has one.
+fn backup_id(path: &Path) -> Result<Option<Uuid>> {
+    if path.is_dir() {
+        Manifest::read_id(path)
+    } else if path
+        .to_string_lossy()
+        .ends_with(&format!(".{TAR_GZ_EXTENSION}"))
+    {
+        archive_id(path)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Finds the one backup in `dirs` with the given ID.
+pub fn find_by_id(id: Uuid, dirs: &[PathBuf]) -> Result<PathBuf> {
+    let mut matches = find_backups(dirs)?.into_iter().filter(|b| b.id == id);
+    let Some(first) = matches.next() else {
+        bail!("no backup with ID {id} in {}", display_dirs(dirs));
+    };
+    if let Some(second) = matches.next() {
+        bail!(
+            "ID {id} is held by more than one backup, including {} and {}",
+            first.path.display(),
+            second.path.display()
+        );
+    }
+    Ok(first.path)
+}
+
+fn display_dirs(dirs: &[PathBuf]) -> String {
+    dirs.iter()
+        .map(|d| d.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Directories to look for backups in: `dirs` plus the `dest` of each
+/// config file, or the current directory if that leaves none.
+pub fn search_dirs(dirs: &[PathBuf], configs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut search = dirs.to_vec();
+    for path in configs {
+        let Some(dest) = Config::from_file(path)?.dest else {
+            bail!("{} sets no `dest` to look for backups in", path.display());
+        };
+        if !search.contains(&dest) {
+            search.push(dest);
+        }
+    }
+    if search.is_empty() {
+        search.push(PathBuf::from("."));
+    }
+    Ok(search)
+}
+
+/// Runs `rbak list`.
+pub fn run(args: &ListArgs) -> Result<()> {
+    for backup in find_backups(&search_dirs(&args.dirs, &args.config)?)? {
+        if args.id_only {
+            println!("{}", backup.id);
+        } else {
+            println!("{}  {}", backup.id, backup.path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{archive::archive_directory, backup_directory_with, BackupOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backups_keep_stable_ids() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        let out = tmp.path().join("backups");
+        let opts = BackupOptions {
+            write_manifest: true,
+            ..Default::default()
+        };
+        let first = out.join("data_bak");
+        backup_directory_with(&src, &first, &opts).unwrap();
+        backup_directory_with(&src, &out.join("other_bak"), &opts).unwrap();
+        backup_directory_with(&src, &out.join("plain_bak"), &BackupOptions::default()).unwrap();
+        let (archive, _) = archive_directory(&src, &out.join("data_bak"), &opts, false).unwrap();
+
+        let found = find_backups(std::slice::from_ref(&out)).unwrap();
+        let paths: Vec<_> = found.iter().map(|b| b.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [first.clone(), archive.clone(), out.join("other_bak")]
+        );
+        assert_ne!(found[0].id, found[2].id);
+        assert_eq!(found[0].id.get_version_num(), 4);
+
+        // Updating a backup in place keeps its ID.
+        let incremental = BackupOptions {
+            write_manifest: true,
+            incremental: true,
+            ..Default::default()
+        };
+        fs::write(src.join("b.txt"), b"beta").unwrap();
+        backup_directory_with(&src, &first, &incremental).unwrap();
+        assert_eq!(Manifest::read_id(&first).unwrap(), Some(found[0].id));
+
+        let dirs = [out.clone()];
+        assert_eq!(find_by_id(found[1].id, &dirs).unwrap(), archive);
+        let err = find_by_id(Uuid::new_v4(), &dirs).unwrap_err();
+        assert!(err.to_string().contains("no backup with ID"));
+
+        // A config file's `dest` is searched too, once.
+        let config = tmp.path().join("rbak.toml");
+        let dest = toml::Value::String(out.display().to_string());
+        fs::write(&config, format!("dest = {dest}\n")).unwrap();
+        let configs = [config.clone()];
+        assert_eq!(search_dirs(&dirs, &configs).unwrap(), dirs);
+        let searched = search_dirs(&[], &configs).unwrap();
+        assert_eq!(find_by_id(found[1].id, &searched).unwrap(), archive);
+        fs::write(&config, "keep = 3\n").unwrap();
+        assert!(search_dirs(&[], &[config]).is_err());
+    }
+}
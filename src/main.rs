@@ -1,10 +1,63 @@
-use anyhow::{Context, Ok, Result};
+mod ads;
+mod archive;
+mod audit;
+mod checksum;
+mod checksum_store;
+mod collision;
+mod compress;
+mod concurrency;
+mod config;
+mod filter;
+mod history;
+mod inplace;
+mod link_dest;
+mod list;
+mod manifest;
+mod ownership;
+mod partial;
+mod restore;
+mod rotate;
+mod source_list;
+mod store;
+mod summary;
+mod verify;
+
+use anyhow::{bail, Context, Result};
+use archive::BackupFormat;
+use audit::AuditArgs;
+use checksum::{HashAlgo, HashFile};
+use checksum_store::ChecksumStore;
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
+use collision::{is_case_sensitive, CaseFoldedNames, CaseFolding};
+use compress::{Compression, Compressor};
+use concurrency::{ConcurrencyModel, CopyBackend, CopyJob};
+use config::{find_cargo_toml, Config};
+use filter::ExcludePattern;
+use history::{History, HistoryArgs, RunRecord, RunStatus};
+use inplace::{inplace_update, INPLACE_BLOCK_SIZE};
+use link_dest::LinkDest;
+use list::ListArgs;
+use manifest::{manifest_key, Manifest, ManifestEntry};
+use ownership::OwnerMap;
+use partial::copy_via_partial;
+use regex::bytes::Regex;
+use restore::RestoreArgs;
+use rotate::{RetentionPolicy, TimestampedBackup};
+use source_list::SourceList;
 use std::{
-    fs::{self},
-    path::{Path, PathBuf},
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, FileTimes, Metadata},
+    io::{BufReader, BufWriter, Read},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
 };
-use tracing::info;
+use store::{ObjectStore, RehydrateArgs};
+use summary::{format_size, SummaryFormat};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+use verify::VerifyArgs;
 
 /// Simple file/directory backup tool (.bak files, _bak directories)
 #[derive(Debug, Parser)]
@@ -12,6 +65,9 @@ use tracing::info;
 pub struct Args {
     #[command(subcommand)]
     command: Commands,
+    /// Record runs in (and read `audit` and `history` data from) this SQLite database
+    #[arg(long, global = true, env = "RBAK_HISTORY_DB", value_name = "PATH")]
+    history_db: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -23,17 +79,354 @@ pub enum Commands {
         /// Optional destination path for backup file
         #[arg(short, long)]
         dest: Option<PathBuf>,
+        #[command(flatten)]
+        common: CommonArgs,
     },
     /// Backup a directory recursively (creates dir_bak)
-    Dir {
-        /// Path to directory to backup
-        path: PathBuf,
-        /// Optional destination path for backup directory
-        #[arg(short, long)]
-        dest: Option<PathBuf>,
+    Dir(Box<DirArgs>),
+    /// Restore a backup file, directory or archive
+    Restore(RestoreArgs),
+    /// Expand a `dir --store` backup into a plain directory tree
+    Rehydrate(RehydrateArgs),
+    /// Report recorded backup runs and flag anomalies
+    Audit(AuditArgs),
+    /// List recorded backup runs
+    History(HistoryArgs),
+    /// Check a backup or archive against its manifest without restoring it
+    Verify(VerifyArgs),
+    /// List backups that have an ID, with their paths
+    List(ListArgs),
+    /// Work with config files
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print a commented config file holding the settings of a command line
+    Generate {
+        #[command(subcommand)]
+        command: GenerateCommand,
     },
 }
 
+/// Command lines `rbak config generate` can turn into a config file.
+#[derive(Debug, Subcommand)]
+pub enum GenerateCommand {
+    /// Take the settings from `rbak dir` arguments
+    Dir(Box<DirArgs>),
+}
+
+impl GenerateCommand {
+    /// Collects the settings a config file can hold, failing on any flag that
+    /// has no config key.
+    fn config(&self) -> Result<Config> {
+        match self {
+            GenerateCommand::Dir(dir) => {
+                let common = &dir.common;
+                let unsupported = [
+                    ("--dry-run", common.dry_run),
+                    ("--canonicalize-dest", common.canonicalize_dest),
+                    ("--rsync-compatible", common.rsync_compatible),
+                    ("--inplace", common.inplace),
+                    ("--partial-dir", common.partial_dir.is_some()),
+                    ("--whole-file", common.whole_file.is_some()),
+                    ("--include-ads", common.include_ads),
+                    ("--hash-file", common.hash_file.is_some()),
+                    ("--source-list-output", common.source_list_output.is_some()),
+                    ("--exclude-by-content", !dir.exclude_by_content.is_empty()),
+                    ("--include-only-zero-byte", dir.include_only_zero_byte),
+                    (
+                        "--content-check-bytes",
+                        dir.content_check_bytes != DEFAULT_CONTENT_CHECK_BYTES,
+                    ),
+                    ("--max-runtime", dir.max_runtime.is_some()),
+                    ("--one-archive-per-dir", dir.one_archive_per_dir),
+                    ("--embed-manifest", dir.embed_manifest),
+                    ("--verify-before-backup", dir.verify_before_backup),
+                    ("--verify-before-prune", dir.verify_before_prune),
+                    ("--link-dest", dir.link_dest.is_some()),
+                    ("--cargo-config", dir.cargo_config.is_some()),
+                    ("--config", dir.config.is_some()),
+                    ("--summary-format", dir.summary_format.is_some()),
+                ];
+                let flags: Vec<_> = unsupported
+                    .iter()
+                    .filter(|(_, set)| *set)
+                    .map(|(flag, _)| *flag)
+                    .collect();
+                if !flags.is_empty() {
+                    bail!(
+                        "{} cannot be written to a config file; pass them on the command line",
+                        flags.join(", ")
+                    );
+                }
+                Ok(Config {
+                    dest: dir.dest.clone(),
+                    exclude: dir.exclude.clone(),
+                    exclude_mime: dir.exclude_mime.clone(),
+                    exclude_zero_byte: dir.exclude_zero_byte,
+                    compress: common.compress,
+                    compress_level: common.compress_level,
+                    format: dir.format,
+                    manifest: dir.manifest,
+                    incremental: dir.incremental,
+                    merge_manifests: dir.merge_manifests,
+                    delete: dir.delete,
+                    detect_renames: dir.detect_renames,
+                    store: dir.store.clone(),
+                    checksum_store: dir.checksum_store.clone(),
+                    concurrency_model: dir.concurrency_model,
+                    keep: dir.keep,
+                    keep_daily: dir.keep_daily,
+                    keep_weekly: dir.keep_weekly,
+                    keep_monthly: dir.keep_monthly,
+                })
+            }
+        }
+    }
+}
+
+/// Flags shared by the `file` and `dir` subcommands.
+#[derive(Debug, clap::Args)]
+pub struct CommonArgs {
+    /// Print what would be done without touching the filesystem
+    #[arg(short = 'n', long, alias = "no-action")]
+    dry_run: bool,
+    /// Compress backed-up files (adds the algorithm's extension, e.g. `.bak.br`)
+    #[arg(long, value_name = "ALGO")]
+    compress: Option<Compression>,
+    /// Compression level (brotli: 0-11, default 11)
+    #[arg(long, value_name = "LEVEL", requires = "compress")]
+    compress_level: Option<u32>,
+    /// Resolve and report the backup path as a canonical absolute path
+    #[arg(long)]
+    canonicalize_dest: bool,
+    /// Preserve permissions and mtimes so `rsync --checksum` sees no differences
+    #[arg(long, conflicts_with = "compress")]
+    rsync_compatible: bool,
+    /// Update existing backup files in place, rewriting only the blocks that changed
+    #[arg(long, conflicts_with = "compress")]
+    inplace: bool,
+    /// Copy through DIR, leaving interrupted copies there to be resumed by the next run
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["compress", "inplace"])]
+    partial_dir: Option<PathBuf>,
+    /// Always copy files matching GLOB (all files if none is given) in full, even with --inplace
+    #[arg(long, value_name = "GLOB", num_args = 0..=1, action = clap::ArgAction::Append)]
+    whole_file: Option<Vec<String>>,
+    /// Also copy NTFS alternate data streams (Windows only)
+    #[arg(long)]
+    include_ads: bool,
+    /// Write `hash  path` lines for every copied file to PATH, or `-` for stdout (`sha256sum -c` format)
+    #[arg(long, value_name = "PATH")]
+    hash_file: Option<PathBuf>,
+    /// Hash algorithm for --hash-file
+    #[arg(
+        long,
+        value_name = "ALGO",
+        default_value = "sha256",
+        requires = "hash_file"
+    )]
+    hash_algo: HashAlgo,
+    /// Write the source path of every copied file to FILE, one per line
+    #[arg(long, value_name = "FILE")]
+    source_list_output: Option<PathBuf>,
+    /// Separate --source-list-output entries with NUL instead of newline
+    #[arg(short = '0', long = "null", requires = "source_list_output")]
+    source_list_null: bool,
+}
+
+impl CommonArgs {
+    /// Builds backup options from the shared flags.
+    fn backup_options(&self) -> Result<BackupOptions> {
+        Ok(BackupOptions {
+            dry_run: DryRunMode::from_flag(self.dry_run),
+            compressor: self
+                .compress
+                .map(|algo| algo.compressor(self.compress_level))
+                .transpose()?,
+            preserve_times: self.rsync_compatible,
+            preserve_permissions: self.rsync_compatible,
+            include_ads: self.include_ads,
+            inplace: self.inplace,
+            partial_dir: self.partial_dir.clone(),
+            whole_file: self
+                .whole_file
+                .as_ref()
+                .map(|globs| globs.iter().map(|g| ExcludePattern::new(g)).collect())
+                .transpose()?,
+            hash_file: self.hash_file.clone(),
+            hash_algo: self.hash_algo,
+            source_list: self.source_list_output.clone(),
+            source_list_null: self.source_list_null,
+            ..Default::default()
+        })
+    }
+
+    /// Applies `--canonicalize-dest` to a computed backup path.
+    fn resolve_dest(&self, bak: PathBuf) -> Result<PathBuf> {
+        if self.canonicalize_dest {
+            canonicalize_dest(&bak)
+        } else {
+            Ok(bak)
+        }
+    }
+}
+
+/// Canonicalizes a backup path that may not exist yet.
+///
+/// The nearest existing ancestor is canonicalized and the missing components
+/// are appended to it, so `backups/new/file.bak` resolves even before
+/// `backups/new` is created. A `..` among them removes the component before it.
+pub fn canonicalize_dest(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path).context("resolving absolute path")?;
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        let (Some(parent), Some(last)) = (existing.parent(), existing.components().next_back())
+        else {
+            break;
+        };
+        missing.push(last);
+        existing = parent;
+    }
+
+    let mut resolved = fs::canonicalize(existing)
+        .with_context(|| format!("canonicalizing {}", existing.display()))?;
+    for component in missing.iter().rev() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Arguments of the `dir` subcommand.
+#[derive(Debug, clap::Args)]
+pub struct DirArgs {
+    /// Path to directory to backup
+    path: PathBuf,
+    /// Optional destination path for backup directory
+    #[arg(short, long)]
+    dest: Option<PathBuf>,
+    /// Skip entries matching a gitignore-style GLOB (`*.tmp`, `target/`, `docs/*.pdf`)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Skip files whose sniffed content type matches PATTERN (e.g. `image/*`)
+    #[arg(long, alias = "exclude-by-mime", value_name = "PATTERN")]
+    exclude_mime: Vec<String>,
+    /// Skip files whose first --content-check-bytes bytes match REGEX (reads every file; slow)
+    #[arg(long, value_name = "REGEX")]
+    exclude_by_content: Vec<Regex>,
+    /// Skip empty (0-byte) files
+    #[arg(long, conflicts_with = "include_only_zero_byte")]
+    exclude_zero_byte: bool,
+    /// Back up only empty (0-byte) files, for diagnostics
+    #[arg(long)]
+    include_only_zero_byte: bool,
+    /// How many leading bytes --exclude-by-content searches
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_CONTENT_CHECK_BYTES)]
+    content_check_bytes: usize,
+    #[command(flatten)]
+    common: CommonArgs,
+    /// Stop after the current file once DURATION has elapsed (e.g. `90s`, `15m`, `2h`)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+    /// Write the backup as a `_bak` directory or a `_bak.tar.gz` archive [default: dir]
+    #[arg(long, value_name = "FORMAT")]
+    format: Option<BackupFormat>,
+    /// With --format tar-gz, write one archive per top-level subdirectory plus one for top-level files
+    #[arg(long)]
+    one_archive_per_dir: bool,
+    /// With --format tar-gz, store a manifest inside each archive for `rbak verify`
+    #[arg(long)]
+    embed_manifest: bool,
+    /// Write a manifest of backed-up files (`.rbak.json`) into the backup
+    #[arg(long, conflicts_with = "rsync_compatible")]
+    manifest: bool,
+    /// Only copy files changed since the manifest in the destination (implies --manifest)
+    #[arg(long, conflicts_with = "rsync_compatible")]
+    incremental: bool,
+    /// Merge this run's entries into the existing manifest instead of replacing it
+    #[arg(long, requires = "incremental")]
+    merge_manifests: bool,
+    /// Delete backed-up files whose source no longer exists
+    #[arg(long, requires = "incremental")]
+    delete: bool,
+    /// Move the backed-up copy of a renamed file (matched by content hash) instead of copying it again
+    #[arg(long, requires = "incremental")]
+    detect_renames: bool,
+    /// Put file contents in a deduplicating object store in DIR; the backup keeps only the manifest
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["compress", "rsync_compatible"])]
+    store: Option<PathBuf>,
+    /// Check the previous backup against its manifest first and abort if it is damaged
+    #[arg(long)]
+    verify_before_backup: bool,
+    /// Back up even if --verify-before-backup finds the previous backup damaged
+    #[arg(long, requires = "verify_before_backup")]
+    force_backup_despite_corrupt_previous: bool,
+    /// With rotation, verify the new backup against its manifest before pruning old ones (implies --manifest)
+    #[arg(long, conflicts_with = "rsync_compatible")]
+    verify_before_prune: bool,
+    /// Copy files with a pool of threads or with async IO [default: threads]
+    #[arg(long, value_name = "MODEL")]
+    concurrency_model: Option<ConcurrencyModel>,
+    /// Cache manifest checksums in DIR so unchanged files are not re-hashed
+    #[arg(long, value_name = "DIR")]
+    checksum_store: Option<PathBuf>,
+    /// Hard-link files unchanged since the backup in PREVIOUS instead of copying them
+    #[arg(long, value_name = "PREVIOUS", conflicts_with_all = ["store", "incremental"])]
+    link_dest: Option<PathBuf>,
+    /// Add a timestamp to the backup name and keep only the newest N backups
+    #[arg(long, value_name = "N")]
+    keep: Option<usize>,
+    /// Keep the newest backup of each of the last N days (implies timestamped names)
+    #[arg(long, value_name = "N")]
+    keep_daily: Option<usize>,
+    /// Keep the newest backup of each of the last N ISO weeks
+    #[arg(long, value_name = "N")]
+    keep_weekly: Option<usize>,
+    /// Keep the newest backup of each of the last N months
+    #[arg(long, value_name = "N")]
+    keep_monthly: Option<usize>,
+    /// Read `[package.metadata.rbak]` from CARGO_TOML (default: nearest above the source)
+    #[arg(long, value_name = "CARGO_TOML", num_args = 0..=1)]
+    cargo_config: Option<Option<PathBuf>>,
+    /// Read settings from a config file (see `rbak config generate`)
+    #[arg(long, value_name = "FILE", conflicts_with = "cargo_config")]
+    config: Option<PathBuf>,
+    /// Print an end-of-run summary in the given format
+    #[arg(long, value_name = "FORMAT")]
+    summary_format: Option<SummaryFormat>,
+}
+
+/// Parses a duration such as `500ms`, `90`, `90s`, `15m`, `2h` or `1d`.
+///
+/// A bare number is taken as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration `{s}`"))?;
+    let secs = match unit {
+        "ms" => return Ok(Duration::from_millis(value)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit `{unit}` in `{s}`")),
+    };
+    value
+        .checked_mul(secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration `{s}` is too long"))
+}
+
 pub enum BackupType {
     File,
     Directory,
@@ -70,75 +463,1365 @@ pub fn backup_path(path: &Path, kind: BackupType) -> Option<PathBuf> {
     Some(bak_path)
 }
 
+/// Whether modifying operations are carried out or only reported.
+///
+/// Every operation that changes the filesystem asks the mode first, so a new
+/// subcommand gets dry-run support by passing the mode down to its helpers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DryRunMode {
+    /// Perform operations for real.
+    #[default]
+    Apply,
+    /// Print each operation instead of performing it.
+    DryRun,
+}
+
+impl DryRunMode {
+    pub fn from_flag(dry_run: bool) -> Self {
+        if dry_run {
+            DryRunMode::DryRun
+        } else {
+            DryRunMode::Apply
+        }
+    }
+
+    pub fn is_dry_run(self) -> bool {
+        self == DryRunMode::DryRun
+    }
+
+    /// Announces a deletion. Returns `true` if the caller should perform it.
+    pub fn would_delete(self, path: &Path) -> bool {
+        self.announce(format_args!("delete {}", path.display()))
+    }
+
+    /// Announces pruning an old backup, with its size and creation time.
+    /// Returns `true` if the caller should perform it.
+    pub fn would_prune(self, backup: &TimestampedBackup) -> Result<bool> {
+        if !self.is_dry_run() {
+            return Ok(true);
+        }
+        Ok(self.announce(format_args!(
+            "delete {} ({}, created {})",
+            backup.path.display(),
+            format_size(backup.size()? as f64),
+            backup.created.format("%Y-%m-%d %H:%M:%S UTC")
+        )))
+    }
+
+    /// Announces a directory creation. Returns `true` if the caller should perform it.
+    pub fn would_create(self, path: &Path) -> bool {
+        self.announce(format_args!("create {}", path.display()))
+    }
+
+    /// Announces a copy. Returns `true` if the caller should perform it.
+    pub fn would_copy(self, src: &Path, dst: &Path) -> bool {
+        self.announce(format_args!("copy {} -> {}", src.display(), dst.display()))
+    }
+
+    /// Announces a rename. Returns `true` if the caller should perform it.
+    pub fn would_rename(self, from: &Path, to: &Path) -> bool {
+        self.announce(format_args!(
+            "rename {} -> {}",
+            from.display(),
+            to.display()
+        ))
+    }
+
+    /// Announces a hard link. Returns `true` if the caller should perform it.
+    pub fn would_link(self, src: &Path, dst: &Path) -> bool {
+        self.announce(format_args!("link {} -> {}", src.display(), dst.display()))
+    }
+
+    fn announce(self, action: std::fmt::Arguments) -> bool {
+        if self.is_dry_run() {
+            println!("would {action}");
+        }
+        !self.is_dry_run()
+    }
+}
+
+/// Number of leading bytes read when sniffing a file's content type.
+const MIME_SNIFF_LEN: usize = 8192;
+
+/// Default for `--content-check-bytes`.
+const DEFAULT_CONTENT_CHECK_BYTES: usize = 1024;
+
+/// Which files `--exclude-zero-byte` and `--include-only-zero-byte` keep.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ZeroByteFilter {
+    /// Empty or not.
+    #[default]
+    Any,
+    /// Only files with contents.
+    ExcludeEmpty,
+    /// Only empty files.
+    OnlyEmpty,
+}
+
+/// Options controlling which entries of a directory tree get backed up.
+#[derive(Debug, Default)]
+pub struct BackupOptions {
+    /// Glob patterns of files and directories to skip.
+    pub exclude: Vec<ExcludePattern>,
+    /// MIME patterns (`image/png`, `image/*`) of files to skip.
+    pub exclude_mime: Vec<String>,
+    /// Regexes skipping files whose first `content_check_bytes` bytes match.
+    pub exclude_content: Vec<Regex>,
+    pub content_check_bytes: usize,
+    /// Filter on whether files are empty.
+    pub zero_byte: ZeroByteFilter,
+    /// Whether files are actually written.
+    pub dry_run: DryRunMode,
+    /// Wall-clock instant after which no further files are started.
+    pub deadline: Option<Instant>,
+    /// Whether to probe the destination for case-insensitive name collisions.
+    pub case_folding: CaseFolding,
+    /// Compresses each file on its way into the backup when set.
+    pub compressor: Option<Box<dyn Compressor>>,
+    /// Copy access and modification times onto backed-up files and directories.
+    pub preserve_times: bool,
+    /// Copy permission bits onto backed-up files and directories.
+    pub preserve_permissions: bool,
+    /// Rewrite only the changed blocks of backup files that already exist.
+    pub inplace: bool,
+    /// Directory that copies are written to before being moved into place,
+    /// so an interrupted copy can be resumed.
+    pub partial_dir: Option<PathBuf>,
+    /// Files always copied in full despite `inplace`: all of them if the
+    /// list is empty, otherwise those matching a pattern.
+    pub whole_file: Option<Vec<ExcludePattern>>,
+    /// Translated owners given to restored files (`--owner-map`, `--group-map`).
+    pub owners: Option<OwnerMap>,
+    /// Copy NTFS alternate data streams along with each file's contents.
+    pub include_ads: bool,
+    /// Write a [`Manifest`] into the backup root.
+    pub write_manifest: bool,
+    /// Skip files whose size and mtime match the destination's manifest.
+    pub incremental: bool,
+    /// Start the new manifest from the existing one so it keeps describing
+    /// everything in the backup, not just what this run saw.
+    pub merge_manifests: bool,
+    /// Remove backed-up files (and their manifest entries) whose source is gone.
+    pub delete: bool,
+    /// Move the backed-up copy of a file whose source was renamed, matched by
+    /// content hash, instead of copying it again.
+    pub detect_renames: bool,
+    /// Store contents in this [`ObjectStore`] instead of copying files into
+    /// the backup; needs `write_manifest` to be restorable.
+    pub store: Option<PathBuf>,
+    /// Directory of a [`ChecksumStore`] used for manifest hashes.
+    pub checksum_store: Option<PathBuf>,
+    /// Previous backup to hard-link unchanged files from instead of copying.
+    pub link_dest: Option<PathBuf>,
+    /// How plain (uncompressed) file copies run.
+    pub concurrency: ConcurrencyModel,
+    /// Text file receiving a `hash  path` line per copied file, paths relative
+    /// to the source root.
+    pub hash_file: Option<PathBuf>,
+    pub hash_algo: HashAlgo,
+    /// File receiving the source path of every copied file.
+    pub source_list: Option<PathBuf>,
+    /// Terminate source list entries with NUL rather than newline.
+    pub source_list_null: bool,
+}
+
+impl BackupOptions {
+    /// Returns `true` if the entry at `path` should be left out of the backup.
+    ///
+    /// `rel` is the entry's path relative to the source root.
+    pub fn is_excluded(&self, path: &Path, rel: &Path, is_dir: bool) -> Result<bool> {
+        if self.exclude.iter().any(|p| p.matches(rel, is_dir)) {
+            info!("Skipping {} (excluded)", path.display());
+            return Ok(true);
+        }
+        if !is_dir && !self.exclude_mime.is_empty() {
+            if let Some(mime) = sniff_mime(path)? {
+                if self.exclude_mime.iter().any(|p| mime_matches(p, mime)) {
+                    info!("Skipping {} ({})", path.display(), mime);
+                    return Ok(true);
+                }
+            }
+        }
+        if !is_dir && self.zero_byte != ZeroByteFilter::Any {
+            let meta = fs::metadata(path)
+                .with_context(|| format!("reading metadata of {}", path.display()))?;
+            let empty = meta.len() == 0;
+            if empty != (self.zero_byte == ZeroByteFilter::OnlyEmpty) {
+                let reason = if empty { "empty" } else { "not empty" };
+                info!("Skipping {} ({reason})", path.display());
+                return Ok(true);
+            }
+        }
+        if !is_dir && !self.exclude_content.is_empty() {
+            let head = read_head(path, self.content_check_bytes)?;
+            if let Some(re) = self.exclude_content.iter().find(|re| re.is_match(&head)) {
+                info!("Skipping {} (content matches `{}`)", path.display(), re);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns where a file destined for `dst` is written, adding the
+    /// compressor's extension (`file.bak` → `file.bak.br`) when compressing.
+    pub fn target_path(&self, dst: &Path) -> PathBuf {
+        match &self.compressor {
+            Some(compressor) => {
+                let mut name = OsString::from(dst.as_os_str());
+                name.push(".");
+                name.push(compressor.extension());
+                PathBuf::from(name)
+            }
+            None => dst.to_path_buf(),
+        }
+    }
+
+    /// Whether the file at `rel` (relative to the source root) is updated in
+    /// place rather than copied whole.
+    pub fn updates_inplace(&self, rel: &Path) -> bool {
+        let whole = match &self.whole_file {
+            Some(globs) => globs.is_empty() || globs.iter().any(|g| g.matches(rel, false)),
+            None => false,
+        };
+        self.inplace && !whole
+    }
+
+    /// Carries the metadata selected by `opts` over from `src` to `dst`.
+    ///
+    /// Times go first: a read-only mode would otherwise stop us opening `dst`.
+    fn preserve_metadata(&self, src: &Path, dst: &Path) -> Result<()> {
+        if self.preserve_times {
+            copy_times(&fs::metadata(src).context("reading source metadata")?, dst)?;
+        }
+        if self.preserve_permissions {
+            preserve_permissions(src, dst)?;
+        }
+        if let Some(owners) = &self.owners {
+            owners.apply(src, dst)?;
+        }
+        Ok(())
+    }
+
+    /// Makes an existing `dst` writable again if a previous run copied a
+    /// read-only mode onto it; the source's mode is stamped back once `dst`
+    /// has been written.
+    fn unlock(&self, dst: &Path) -> Result<()> {
+        if !self.preserve_permissions {
+            return Ok(());
+        }
+        let Ok(meta) = fs::metadata(dst) else {
+            return Ok(());
+        };
+        let mut perms = meta.permissions();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if perms.mode() & 0o200 != 0 {
+                return Ok(());
+            }
+            perms.set_mode(perms.mode() | 0o200);
+        }
+        #[cfg(not(unix))]
+        {
+            if !perms.readonly() {
+                return Ok(());
+            }
+            #[allow(clippy::permissions_set_readonly_false)]
+            perms.set_readonly(false);
+        }
+        fs::set_permissions(dst, perms)
+            .with_context(|| format!("making {} writable", dst.display()))
+    }
+
+    /// Opens the per-file outputs (`--hash-file`, `--source-list-output`)
+    /// requested by these options. None are written on a dry run.
+    fn observers(&self) -> Result<Vec<Box<dyn CopyObserver>>> {
+        let mut observers: Vec<Box<dyn CopyObserver>> = Vec::new();
+        if self.dry_run.is_dry_run() {
+            return Ok(observers);
+        }
+        if let Some(path) = &self.hash_file {
+            observers.push(Box::new(HashFile::create(path, self.hash_algo)?));
+        }
+        if let Some(path) = &self.source_list {
+            observers.push(Box::new(SourceList::create(path, self.source_list_null)?));
+        }
+        Ok(observers)
+    }
+}
+
+/// Notified of every file a backup copies.
+pub trait CopyObserver {
+    /// Called after `src` was copied; `name` is its `/`-separated path
+    /// relative to the source root (the file name for single-file backups).
+    fn file_copied(&mut self, src: &Path, name: &str) -> Result<()>;
+
+    /// Called once the backup has finished.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Stamps `dst` with the access and modification times from `src_meta`.
+pub fn copy_times(src_meta: &Metadata, dst: &Path) -> Result<()> {
+    let times = FileTimes::new()
+        .set_accessed(src_meta.accessed().context("reading access time")?)
+        .set_modified(src_meta.modified().context("reading modification time")?);
+    let handle = if cfg!(unix) {
+        // Setting times takes ownership, not write access, so read-only
+        // copies (which `fs::copy` makes of read-only sources) work too.
+        File::open(dst)
+    } else if src_meta.is_dir() {
+        // Windows cannot open directories through `File`, so their times are
+        // only kept on Unix.
+        return Ok(());
+    } else {
+        File::options().write(true).open(dst)
+    };
+    handle
+        .and_then(|f| f.set_times(times))
+        .with_context(|| format!("setting times on {}", dst.display()))
+}
+
+/// Gives `dst` the same permission bits as `src`.
+pub fn preserve_permissions(src: &Path, dst: &Path) -> Result<()> {
+    let perms = fs::metadata(src)
+        .context("reading source permissions")?
+        .permissions();
+    fs::set_permissions(dst, perms)
+        .with_context(|| format!("setting permissions on {}", dst.display()))
+}
+
+/// Counts of what a backup run copied or skipped.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BackupStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+    pub skipped: u64,
+    /// Files left alone or hard-linked (`--link-dest`) because the manifest
+    /// showed them unchanged.
+    pub unchanged: u64,
+    /// Backed-up files removed because their source disappeared.
+    pub deleted: u64,
+    /// Backed-up files moved to follow a renamed source (`--detect-renames`).
+    pub renamed: u64,
+    /// Set when the run stopped early because the deadline was reached.
+    pub timed_out: bool,
+    /// Wall-clock time the run took.
+    pub duration: Duration,
+}
+
+impl BackupStats {
+    /// Bytes copied per second of wall-clock time, or 0 for an instant run.
+    pub fn throughput_bps(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Detects a file's content type from its magic bytes, ignoring the extension.
+///
+/// Only the first `MIME_SNIFF_LEN` bytes are read. Returns `None` for unknown types.
+pub fn sniff_mime(path: &Path) -> Result<Option<&'static str>> {
+    let header = read_head(path, MIME_SNIFF_LEN)?;
+    Ok(infer::get(&header).map(|kind| kind.mime_type()))
+}
+
+/// Reads at most the first `len` bytes of a file.
+fn read_head(path: &Path, len: usize) -> Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(len);
+    File::open(path)
+        .with_context(|| format!("opening {}", path.display()))?
+        .take(len as u64)
+        .read_to_end(&mut header)
+        .context("reading file header")?;
+    Ok(header)
+}
+
+/// Matches a MIME type against a pattern; `type/*` matches any subtype.
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(top) => mime.split('/').next() == Some(top),
+        None => pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+/// Copies (or compresses) a single file to `dst`, which should come from
+/// [`BackupOptions::target_path`].
+///
+/// Returns the number of source bytes read (with `inplace`, the bytes
+/// rewritten in an existing backup), or 0 on a dry run.
+pub fn backup_file(src: &Path, dst: &Path, opts: &BackupOptions) -> Result<u64> {
+    let name = Path::new(src.file_name().unwrap_or_default());
+    backup_file_at(src, dst, name, opts)
+}
+
+/// Like [`backup_file`], for a file at `rel` relative to the source root, as
+/// matched by `--whole-file` patterns.
+fn backup_file_at(src: &Path, dst: &Path, rel: &Path, opts: &BackupOptions) -> Result<u64> {
+    if !opts.dry_run.would_copy(src, dst) {
+        return Ok(0);
+    }
+    opts.unlock(dst)?;
+    let mut bytes = match &opts.compressor {
+        Some(compressor) => {
+            let mut reader = BufReader::new(File::open(src).context("opening source file")?);
+            let mut writer = BufWriter::new(File::create(dst).context("creating backup file")?);
+            compressor.compress(&mut reader, &mut writer)?
+        }
+        None if opts.updates_inplace(rel) && dst.is_file() => {
+            inplace_update(src, dst, INPLACE_BLOCK_SIZE)?
+        }
+        None => match &opts.partial_dir {
+            Some(partial_dir) => copy_via_partial(src, dst, &partial_dir.join(rel))?,
+            None => fs::copy(src, dst).context("copying file")?,
+        },
+    };
+    bytes += finish_copy(src, dst, opts)?;
+    Ok(bytes)
+}
+
+/// Completes a copy of `src` whose contents are already in `dst`: alternate
+/// data streams and metadata. Returns the stream bytes copied.
+fn finish_copy(src: &Path, dst: &Path, opts: &BackupOptions) -> Result<u64> {
+    let streams = ads::alternate_streams(src)?;
+    let mut bytes = 0;
+    if opts.include_ads {
+        bytes += ads::copy_streams(src, dst, &streams)?;
+    } else if !streams.is_empty() {
+        debug!(
+            "Dropping {} alternate data streams of {} (see --include-ads)",
+            streams.len(),
+            src.display()
+        );
+    }
+    opts.preserve_metadata(src, dst)?;
+    Ok(bytes)
+}
+
+/// Hard-links `previous` to `dst`, replacing whatever `dst` held before.
+fn link_file(previous: &Path, dst: &Path) -> Result<()> {
+    match fs::remove_file(dst) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("replacing {}", dst.display()));
+        }
+        _ => {}
+    }
+    fs::hard_link(previous, dst)
+        .with_context(|| format!("linking {} to {}", dst.display(), previous.display()))
+}
+
 /// Recursively copies a directory tree to the destination.
 ///
 /// Creates all necessary parent directories and handles files/subdirectories.
-pub fn backup_directory(src: &Path, dst: &Path) -> Result<()> {
-    fs::create_dir_all(dst).context("creating backup directory tree")?;
-
-    for entry in fs::read_dir(src).context("reading source directory")? {
-        let entry = entry.context("reading directory entry")?;
-        let file_type = entry.file_type().context("getting file type")?;
-        let src_path = entry.path();
-        let mut dst_path = PathBuf::from(dst);
-        dst_path.push(entry.file_name());
+pub fn backup_directory(src: &Path, dst: &Path) -> Result<BackupStats> {
+    backup_directory_with(src, dst, &BackupOptions::default())
+}
 
-        if file_type.is_dir() {
-            backup_directory(&src_path, &dst_path)?;
-        } else if file_type.is_file() {
-            fs::copy(&src_path, &dst_path).context("copying file")?;
+/// Like [`backup_directory`], but honours the filters and limits in `opts`.
+///
+/// When the deadline passes, the file in flight is finished and the walk stops
+/// with [`BackupStats::timed_out`] set.
+pub fn backup_directory_with(src: &Path, dst: &Path, opts: &BackupOptions) -> Result<BackupStats> {
+    let started = Instant::now();
+    let previous = if opts.incremental {
+        Manifest::load(dst)?.unwrap_or_default()
+    } else {
+        Manifest::default()
+    };
+    // Compressed copies are renamed, so restoring them needs the manifest.
+    let write_manifest = opts.write_manifest || opts.compressor.is_some();
+    let manifest = (write_manifest && !opts.dry_run.is_dry_run()).then(|| {
+        let mut manifest = if opts.merge_manifests {
+            previous.clone()
+        } else {
+            Manifest::default()
+        };
+        // An incremental run updates the same backup, which keeps its ID.
+        manifest.id = Some(previous.id.unwrap_or_else(Uuid::new_v4));
+        manifest
+    });
+    // Content hashes of previous entries whose source is gone, for rename detection.
+    let mut renames: HashMap<String, Vec<String>> = HashMap::new();
+    if opts.detect_renames {
+        for (key, entry) in &previous.entries {
+            if !src.join(entry.source_path(key)).exists() {
+                renames
+                    .entry(entry.blake3.clone())
+                    .or_default()
+                    .push(key.clone());
+            }
         }
     }
-    Ok(())
+    let observers = opts.observers()?;
+    let checksums = opts
+        .checksum_store
+        .as_deref()
+        .filter(|_| manifest.is_some())
+        .map(ChecksumStore::open)
+        .transpose()?;
+    let store = opts
+        .store
+        .as_deref()
+        .map(|root| ObjectStore::open(root, opts.dry_run))
+        .transpose()?;
+    let link_dest = opts
+        .link_dest
+        .as_deref()
+        .map(|root| LinkDest::open(root, dst, opts.dry_run))
+        .transpose()?;
+    let backend = opts.concurrency.backend()?;
+    // Probe the nearest existing directory, since `dst` may not exist yet.
+    let case_insensitive = match (opts.case_folding, dst.ancestors().find(|p| p.is_dir())) {
+        (CaseFolding::Assume, _) => true,
+        (CaseFolding::Detect, Some(existing)) if !opts.dry_run.is_dry_run() => {
+            !is_case_sensitive(existing)?
+        }
+        _ => false,
+    };
+
+    let mut walk = Walk {
+        opts,
+        src_root: src,
+        dst_root: dst,
+        previous,
+        renames,
+        manifest,
+        observers,
+        checksums,
+        store,
+        link_dest,
+        backend,
+        pending: Vec::new(),
+        case_insensitive,
+        stats: BackupStats::default(),
+    };
+    walk.copy_tree(src, dst)?;
+    if opts.delete {
+        walk.delete_removed(dst)?;
+    }
+    if let Some(manifest) = &walk.manifest {
+        manifest.save(dst)?;
+    }
+    for observer in walk.observers.drain(..) {
+        observer.finish()?;
+    }
+    if let Some(checksums) = walk.checksums.take() {
+        checksums.commit()?;
+    }
+    walk.stats.duration = started.elapsed();
+    Ok(walk.stats)
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+/// State threaded through one directory backup.
+struct Walk<'a> {
+    opts: &'a BackupOptions,
+    src_root: &'a Path,
+    dst_root: &'a Path,
+    /// Manifest found in the destination; empty unless running incrementally.
+    previous: Manifest,
+    /// Keys of previous entries whose source is gone, by content hash; empty
+    /// unless detecting renames.
+    renames: HashMap<String, Vec<String>>,
+    /// Manifest built by this run, if one is being written.
+    manifest: Option<Manifest>,
+    observers: Vec<Box<dyn CopyObserver>>,
+    /// Cache of manifest hashes, if `--checksum-store` was given.
+    checksums: Option<ChecksumStore>,
+    /// Object store receiving file contents, if backing up with `--store`.
+    store: Option<ObjectStore>,
+    /// Previous backup to hard-link unchanged files from.
+    link_dest: Option<LinkDest>,
+    backend: Box<dyn CopyBackend>,
+    /// Plain copies queued for `backend`, run when their directory is done.
+    pending: Vec<PendingCopy>,
+    /// Whether the destination folds case, so `a.txt` and `A.txt` would collide.
+    case_insensitive: bool,
+    stats: BackupStats,
+}
 
-    let args = Args::parse();
+impl Walk<'_> {
+    fn copy_tree(&mut self, src: &Path, dst: &Path) -> Result<()> {
+        let opts = self.opts;
+        // Store backups hold only the manifest, so only their root is created.
+        if (self.store.is_none() || src == self.src_root) && opts.dry_run.would_create(dst) {
+            fs::create_dir_all(dst).context("creating backup directory tree")?;
+            opts.unlock(dst)?;
+        }
+        self.stats.dirs += 1;
 
-    match args.command {
-        Commands::File { path, dest } => {
-            info!("Backing up file: {}", path.display());
-
-            let bak = if let Some(dest_dir) = dest {
-                // Custom dest: dest_dir/filename.bak
-                let mut bak = dest_dir;
-                if let Some(name) = path.file_name() {
-                    bak.push(Path::new(name).with_extension("bak"));
+        let mut entries = fs::read_dir(src)
+            .context("reading source directory")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("reading directory entry")?;
+        // Sorted so the same one of two colliding names is renamed every run.
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut names = self.case_insensitive.then(CaseFoldedNames::default);
+
+        for entry in entries {
+            let file_type = entry.file_type().context("getting file type")?;
+            let src_path = entry.path();
+            let rel = src_path.strip_prefix(self.src_root).unwrap_or(&src_path);
+
+            if opts.is_excluded(&src_path, rel, file_type.is_dir())? {
+                self.stats.skipped += 1;
+                continue;
+            }
+            let mut dst_name = entry.file_name();
+            if let Some(names) = &mut names {
+                let claimed = names.claim(&dst_name);
+                if claimed != dst_name {
+                    warn!(
+                        "{} collides with another name on the case-insensitive destination; backing it up as {}",
+                        src_path.display(),
+                        claimed.to_string_lossy()
+                    );
+                    dst_name = claimed;
                 }
-                bak
-            } else {
-                // Default: same dir as source
-                backup_path(&path, BackupType::File)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid file"))?
+            }
+            let dst_path = dst.join(dst_name);
+
+            if file_type.is_dir() {
+                self.copy_tree(&src_path, &dst_path)?;
+            } else if file_type.is_file() {
+                self.copy_file(&src_path, &dst_path, rel)?;
+            }
+            if self.stats.timed_out {
+                break;
+            }
+        }
+        self.run_pending()?;
+
+        // Directory times change as entries are written, so stamp them last.
+        if !opts.dry_run.is_dry_run() {
+            opts.preserve_metadata(src, dst)?;
+        }
+        Ok(())
+    }
+
+    fn copy_file(&mut self, src: &Path, dst: &Path, rel: &Path) -> Result<()> {
+        let opts = self.opts;
+        // Keyed by the name written, which differs from the source's only
+        // after a case collision.
+        let key = manifest_key(dst.strip_prefix(self.dst_root).unwrap_or(rel));
+        let source = Some(manifest_key(rel)).filter(|source| *source != key);
+        let meta = fs::metadata(src).context("reading source metadata")?;
+        if let Some(prev) = self.previous.entries.get(&key).filter(|e| e.matches(&meta)) {
+            self.stats.unchanged += 1;
+            if let Some(manifest) = &mut self.manifest {
+                let entry = ManifestEntry {
+                    source,
+                    ..prev.clone()
+                };
+                manifest.entries.insert(key, entry);
+            }
+            return Ok(());
+        }
+        if self.move_renamed(src, dst, &key, &source, &meta)? {
+            return Ok(());
+        }
+        if let Some(link_dest) = &self.link_dest {
+            let target = opts.target_path(dst);
+            let unchanged = link_dest.unchanged(&key, &meta, |p| opts.target_path(p));
+            if let Some((previous, entry)) = unchanged {
+                if opts.dry_run.would_link(&previous, &target) {
+                    link_file(&previous, &target)?;
+                }
+                self.stats.unchanged += 1;
+                if self.manifest.is_some() {
+                    let entry = match entry {
+                        Some(entry) => entry,
+                        None => ManifestEntry::with_hash(&meta, self.blake3(src, &meta)?),
+                    };
+                    let entry = ManifestEntry { source, ..entry };
+                    if let Some(manifest) = &mut self.manifest {
+                        manifest.entries.insert(key, entry);
+                    }
+                }
+                return Ok(());
+            }
+        }
+
+        let mut hash = None;
+        let bytes = match &self.store {
+            Some(store) => {
+                let blake3 = self.blake3(src, &meta)?;
+                let written = store.put(src, &blake3, opts.dry_run)?;
+                hash = Some(blake3);
+                written
+            }
+            None if opts.compressor.is_none()
+                && !opts.updates_inplace(rel)
+                && opts.partial_dir.is_none()
+                && !opts.dry_run.is_dry_run() =>
+            {
+                // Recorded once the backend has copied it.
+                opts.unlock(dst)?;
+                self.pending.push(PendingCopy {
+                    job: CopyJob {
+                        src: src.to_path_buf(),
+                        dst: dst.to_path_buf(),
+                    },
+                    key,
+                    source,
+                    meta,
+                });
+                // Past the deadline, copy the queue now so the walk stops.
+                if opts.deadline.is_some_and(|d| Instant::now() >= d) {
+                    self.run_pending()?;
+                }
+                return Ok(());
+            }
+            None => backup_file_at(src, &opts.target_path(dst), rel, opts)?,
+        };
+        self.stats.bytes += bytes;
+        self.file_copied(src, key, source, &meta, hash)
+    }
+
+    /// Records a file whose copy is written: counts it, adds its manifest
+    /// entry, tells the observers and checks the deadline.
+    fn file_copied(
+        &mut self,
+        src: &Path,
+        key: String,
+        source: Option<String>,
+        meta: &Metadata,
+        hash: Option<String>,
+    ) -> Result<()> {
+        self.stats.files += 1;
+        let name = source.as_deref().unwrap_or(&key);
+        for observer in &mut self.observers {
+            observer.file_copied(src, name)?;
+        }
+        if self.manifest.is_some() {
+            let blake3 = match hash {
+                Some(hash) => hash,
+                None => self.blake3(src, meta)?,
             };
+            if let Some(manifest) = &mut self.manifest {
+                let entry = ManifestEntry {
+                    source,
+                    ..ManifestEntry::with_hash(meta, blake3)
+                };
+                manifest.entries.insert(key, entry);
+            }
+        }
+        if self.opts.deadline.is_some_and(|d| Instant::now() >= d) {
+            self.stats.timed_out = true;
+        }
+        Ok(())
+    }
+
+    /// Moves the backed-up copy of a previous entry with the same contents as
+    /// `src` over to `dst` if that entry's source is gone, so a renamed file
+    /// is neither copied again nor deleted. Returns `false` if there is none.
+    fn move_renamed(
+        &mut self,
+        src: &Path,
+        dst: &Path,
+        key: &str,
+        source: &Option<String>,
+        meta: &Metadata,
+    ) -> Result<bool> {
+        if self.renames.is_empty() {
+            return Ok(false);
+        }
+        let blake3 = self.blake3(src, meta)?;
+        let Some(old_key) = self.renames.get_mut(&blake3).and_then(Vec::pop) else {
+            return Ok(false);
+        };
+        let opts = self.opts;
+        // Store backups only record the hash; the object itself stays put.
+        if self.store.is_none() {
+            let from = opts.target_path(&self.dst_root.join(&old_key));
+            let to = opts.target_path(dst);
+            if opts.dry_run.would_rename(&from, &to) {
+                fs::rename(&from, &to)
+                    .with_context(|| format!("moving {} to {}", from.display(), to.display()))?;
+                opts.preserve_metadata(src, &to)?;
+            }
+        }
+        info!("Detected rename of {old_key} to {key}");
+        self.previous.entries.remove(&old_key);
+        if let Some(manifest) = &mut self.manifest {
+            manifest.entries.remove(&old_key);
+            let entry = ManifestEntry {
+                source: source.clone(),
+                ..ManifestEntry::with_hash(meta, blake3)
+            };
+            manifest.entries.insert(key.to_string(), entry);
+        }
+        self.stats.renamed += 1;
+        Ok(true)
+    }
 
-            fs::copy(&path, &bak).context("copying file backup")?;
-            info!("Created backup file: {}", bak.display());
+    /// Hands the queued copies to the backend, then finishes and records each
+    /// one in order, stopping at the first that failed.
+    fn run_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let jobs: Vec<_> = pending.iter().map(|copy| copy.job.clone()).collect();
+        let results = self.backend.copy_files(&jobs);
+        for (copy, result) in pending.into_iter().zip(results) {
+            let PendingCopy {
+                job,
+                key,
+                source,
+                meta,
+            } = copy;
+            self.stats.bytes += result? + finish_copy(&job.src, &job.dst, self.opts)?;
+            self.file_copied(&job.src, key, source, &meta, None)?;
         }
-        Commands::Dir { path, dest } => {
-            info!("Backing up directory: {}", path.display());
+        Ok(())
+    }
 
-            let bak_dir = if let Some(dest_dir) = dest {
-                // Build backup path relative to dest_dir, reusing backup_path logic
-                let orig_backup = backup_path(&path, BackupType::Directory)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid directory"))?;
-                let bak_dir_name = orig_backup.file_name().unwrap();
+    /// Hashes a source file, through the checksum store if there is one.
+    fn blake3(&self, src: &Path, meta: &Metadata) -> Result<String> {
+        match &self.checksums {
+            Some(store) => store.blake3(src, meta),
+            None => manifest::hash_file(src),
+        }
+    }
 
-                let mut bak_dir = dest_dir;
-                bak_dir.push(bak_dir_name);
-                bak_dir
-            } else {
-                backup_path(&path, BackupType::Directory)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid directory"))?
+    /// Deletes backed-up copies of files recorded in the previous manifest
+    /// whose source no longer exists, pruning them from the new manifest.
+    fn delete_removed(&mut self, dst: &Path) -> Result<()> {
+        let removed = self
+            .previous
+            .entries
+            .iter()
+            .filter(|(key, entry)| !self.src_root.join(entry.source_path(key)).exists())
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in removed {
+            let target = self.opts.target_path(&dst.join(&key));
+            if self.opts.dry_run.would_delete(&target) {
+                match fs::remove_file(&target) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(e).with_context(|| format!("deleting {}", target.display()));
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(manifest) = &mut self.manifest {
+                manifest.entries.remove(&key);
+            }
+            self.stats.deleted += 1;
+        }
+        Ok(())
+    }
+}
+
+/// A file queued for the copy backend, with what is needed to record it
+/// once copied.
+struct PendingCopy {
+    job: CopyJob,
+    key: String,
+    source: Option<String>,
+    meta: Metadata,
+}
+
+/// Where a finished backup run put its copy and what it did.
+struct RunOutcome {
+    destination: PathBuf,
+    stats: BackupStats,
+    /// ID from the backup's manifest, if it wrote one.
+    backup_id: Option<Uuid>,
+}
+
+fn run_file(path: &Path, dest: Option<PathBuf>, common: &CommonArgs) -> Result<RunOutcome> {
+    info!("Backing up file: {}", path.display());
+    let started = Instant::now();
+
+    let bak = if let Some(dest_dir) = dest {
+        // Custom dest: dest_dir/filename.bak
+        let mut bak = dest_dir;
+        if let Some(name) = path.file_name() {
+            bak.push(Path::new(name).with_extension("bak"));
+        }
+        bak
+    } else {
+        // Default: same dir as source
+        backup_path(path, BackupType::File).ok_or_else(|| anyhow::anyhow!("Invalid file"))?
+    };
+
+    let opts = common.backup_options()?;
+    let bak = common.resolve_dest(opts.target_path(&bak))?;
+    let bytes = backup_file(path, &bak, &opts).context("copying file backup")?;
+    if !opts.dry_run.is_dry_run() {
+        info!("Created backup file: {}", bak.display());
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        for mut observer in opts.observers()? {
+            observer.file_copied(path, &name)?;
+            observer.finish()?;
+        }
+    }
+
+    Ok(RunOutcome {
+        destination: bak,
+        stats: BackupStats {
+            files: 1,
+            bytes,
+            duration: started.elapsed(),
+            ..Default::default()
+        },
+        backup_id: None,
+    })
+}
+
+fn run_dir(args: DirArgs) -> Result<RunOutcome> {
+    let DirArgs {
+        path,
+        dest,
+        mut exclude,
+        mut exclude_mime,
+        exclude_by_content,
+        exclude_zero_byte,
+        include_only_zero_byte,
+        content_check_bytes,
+        mut common,
+        max_runtime,
+        format,
+        one_archive_per_dir,
+        embed_manifest,
+        manifest,
+        incremental,
+        merge_manifests,
+        delete,
+        detect_renames,
+        store,
+        verify_before_backup,
+        force_backup_despite_corrupt_previous,
+        verify_before_prune,
+        checksum_store,
+        link_dest,
+        concurrency_model,
+        keep,
+        keep_daily,
+        keep_weekly,
+        keep_monthly,
+        cargo_config,
+        config,
+        summary_format,
+    } = args;
+    info!("Backing up directory: {}", path.display());
+
+    let config = match (cargo_config, config) {
+        (Some(explicit), _) => {
+            let cargo_toml = match explicit {
+                Some(cargo_toml) => cargo_toml,
+                None => find_cargo_toml(&path).ok_or_else(|| {
+                    anyhow::anyhow!("No Cargo.toml found above {}", path.display())
+                })?,
             };
+            info!("Using rbak config from {}", cargo_toml.display());
+            Config::from_cargo_toml(&cargo_toml)?
+        }
+        (None, Some(file)) => {
+            info!("Using rbak config from {}", file.display());
+            Config::from_file(&file)?
+        }
+        (None, None) => Config::default(),
+    };
+    // Flags take precedence over the config; list settings add up.
+    let dest = dest.or(config.dest);
+    exclude.extend(config.exclude);
+    exclude_mime.extend(config.exclude_mime);
+    let exclude_zero_byte = exclude_zero_byte || config.exclude_zero_byte;
+    common.compress = common.compress.or(config.compress);
+    common.compress_level = common.compress_level.or(config.compress_level);
+    let format = format.or(config.format).unwrap_or_default();
+    let manifest = manifest || config.manifest;
+    let incremental = incremental || config.incremental;
+    let merge_manifests = merge_manifests || config.merge_manifests;
+    let delete = delete || config.delete;
+    let detect_renames = detect_renames || config.detect_renames;
+    let store = store.or(config.store);
+    let checksum_store = checksum_store.or(config.checksum_store);
+    let concurrency_model = concurrency_model
+        .or(config.concurrency_model)
+        .unwrap_or_default();
+    // clap only checks these between flags, not against config settings.
+    let requires = [
+        (
+            "--compress-level",
+            common.compress_level.is_some(),
+            "--compress",
+            common.compress.is_some(),
+        ),
+        (
+            "--merge-manifests",
+            merge_manifests,
+            "--incremental",
+            incremental,
+        ),
+        ("--delete", delete, "--incremental", incremental),
+        (
+            "--detect-renames",
+            detect_renames,
+            "--incremental",
+            incremental,
+        ),
+    ];
+    if let Some((flag, _, needed, _)) = requires.iter().find(|(_, set, _, met)| *set && !met) {
+        bail!("{flag} requires {needed}");
+    }
+    let compress = common.compress.is_some();
+    let conflicts = [
+        (
+            "--compress",
+            compress,
+            "--rsync-compatible",
+            common.rsync_compatible,
+        ),
+        ("--compress", compress, "--inplace", common.inplace),
+        (
+            "--compress",
+            compress,
+            "--partial-dir",
+            common.partial_dir.is_some(),
+        ),
+        ("--store", store.is_some(), "--compress", compress),
+        (
+            "--store",
+            store.is_some(),
+            "--rsync-compatible",
+            common.rsync_compatible,
+        ),
+        (
+            "--link-dest",
+            link_dest.is_some(),
+            "--store",
+            store.is_some(),
+        ),
+        (
+            "--link-dest",
+            link_dest.is_some(),
+            "--incremental",
+            incremental,
+        ),
+        (
+            "--manifest",
+            manifest,
+            "--rsync-compatible",
+            common.rsync_compatible,
+        ),
+        (
+            "--incremental",
+            incremental,
+            "--rsync-compatible",
+            common.rsync_compatible,
+        ),
+        (
+            "--verify-before-prune",
+            verify_before_prune,
+            "--rsync-compatible",
+            common.rsync_compatible,
+        ),
+    ];
+    if let Some((flag, _, other, _)) = conflicts.iter().find(|(_, a, _, b)| *a && *b) {
+        bail!("{flag} cannot be combined with {other}");
+    }
+    let keep = keep.or(config.keep);
+    if keep == Some(0) {
+        bail!("--keep must be at least 1");
+    }
+    let retention = RetentionPolicy {
+        keep_last: keep.unwrap_or(0),
+        daily: keep_daily.or(config.keep_daily).unwrap_or(0),
+        weekly: keep_weekly.or(config.keep_weekly).unwrap_or(0),
+        monthly: keep_monthly.or(config.keep_monthly).unwrap_or(0),
+    };
+    if format != BackupFormat::TarGz {
+        if one_archive_per_dir {
+            bail!("--one-archive-per-dir requires --format tar-gz");
+        }
+        if embed_manifest {
+            bail!("--embed-manifest requires --format tar-gz; directory backups use --manifest");
+        }
+    }
+    if format == BackupFormat::TarGz {
+        let directory_only = [
+            ("--manifest", manifest),
+            ("--incremental", incremental),
+            ("--store", store.is_some()),
+            ("--link-dest", link_dest.is_some()),
+            ("--compress", common.compress.is_some()),
+            ("--inplace", common.inplace),
+            ("--hash-file", common.hash_file.is_some()),
+            ("--source-list-output", common.source_list_output.is_some()),
+            ("--verify-before-backup", verify_before_backup),
+            ("rotation (--keep and friends)", !retention.is_empty()),
+        ];
+        if let Some((flag, _)) = directory_only.iter().find(|(_, set)| *set) {
+            bail!("{flag} cannot be combined with --format tar-gz");
+        }
+    }
+
+    let bak_dir = if let Some(dest_dir) = dest {
+        // Build backup path relative to dest_dir, reusing backup_path logic
+        let orig_backup = backup_path(&path, BackupType::Directory)
+            .ok_or_else(|| anyhow::anyhow!("Invalid directory"))?;
+        let bak_dir_name = orig_backup.file_name().unwrap();
+
+        let mut bak_dir = dest_dir;
+        bak_dir.push(bak_dir_name);
+        bak_dir
+    } else {
+        backup_path(&path, BackupType::Directory)
+            .ok_or_else(|| anyhow::anyhow!("Invalid directory"))?
+    };
+    // Rotated backups carry a timestamp: dir_bak_20261015T120000Z
+    let bak_base = bak_dir.file_name().unwrap().to_string_lossy().into_owned();
+    let bak_parent = bak_dir
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
 
-            backup_directory(&path, &bak_dir).context("directory backup")?;
+    if verify_before_backup {
+        // The backup about to be overwritten, or the newest one in rotation.
+        let previous = if retention.is_empty() {
+            Some(bak_dir.clone()).filter(|dir| dir.is_dir())
+        } else if bak_parent.is_dir() {
+            rotate::list_backups(&bak_parent, &bak_base)?
+                .into_iter()
+                .next()
+                .map(|backup| backup.path)
+        } else {
+            None
+        };
+        if let Some(previous) = previous {
+            info!("Verifying previous backup {}", previous.display());
+            let dry_run = DryRunMode::from_flag(common.dry_run);
+            let store = store
+                .as_deref()
+                .map(|root| ObjectStore::open(root, dry_run))
+                .transpose()?;
+            verify::ensure_intact(
+                &previous,
+                store.as_ref(),
+                force_backup_despite_corrupt_previous,
+            )?;
+        }
+    }
+    let bak_dir = if retention.is_empty() {
+        bak_dir
+    } else {
+        bak_dir.with_file_name(rotate::timestamped_name(&bak_base, Utc::now()))
+    };
+    let bak_dir = common.resolve_dest(bak_dir)?;
+
+    let opts = BackupOptions {
+        exclude: exclude
+            .iter()
+            .map(|p| ExcludePattern::new(p))
+            .collect::<Result<_>>()?,
+        exclude_mime,
+        exclude_content: exclude_by_content,
+        content_check_bytes,
+        zero_byte: if exclude_zero_byte {
+            ZeroByteFilter::ExcludeEmpty
+        } else if include_only_zero_byte {
+            ZeroByteFilter::OnlyEmpty
+        } else {
+            ZeroByteFilter::Any
+        },
+        // A limit too far off to represent is no limit.
+        deadline: max_runtime.and_then(|limit| Instant::now().checked_add(limit)),
+        write_manifest: manifest
+            || incremental
+            || store.is_some()
+            || verify_before_prune
+            || embed_manifest,
+        incremental,
+        merge_manifests,
+        delete,
+        detect_renames,
+        store,
+        checksum_store,
+        link_dest,
+        concurrency: concurrency_model,
+        ..common.backup_options()?
+    };
+    let (destination, stats) = match format {
+        BackupFormat::Dir => {
+            let stats =
+                backup_directory_with(&path, &bak_dir, &opts).context("directory backup")?;
+            (bak_dir.clone(), stats)
+        }
+        BackupFormat::TarGz => {
+            archive::archive_directory(&path, &bak_dir, &opts, one_archive_per_dir)
+                .context("archive backup")?
+        }
+    };
+    if let Some(format) = summary_format {
+        println!("{}", format.render(&stats));
+    } else if stats.timed_out {
+        // Unasked-for output goes to stderr, keeping `--hash-file -` clean.
+        eprintln!(
+            "Max runtime reached; backup of {} is partial: {} files ({} bytes) copied",
+            bak_dir.display(),
+            stats.files,
+            stats.bytes
+        );
+    } else {
+        eprintln!("{}", SummaryFormat::Human.render(&stats));
+        if one_archive_per_dir {
+            info!("Created backup archives in: {}", destination.display());
+        } else if format == BackupFormat::TarGz {
+            info!("Created backup archive: {}", destination.display());
+        } else {
             info!("Created backup directory: {}", bak_dir.display());
         }
     }
 
+    // Only rotate once the new backup is complete.
+    if !retention.is_empty() && !stats.timed_out {
+        let pruned = if verify_before_prune {
+            let store = opts
+                .store
+                .as_deref()
+                .map(|root| ObjectStore::open(root, opts.dry_run))
+                .transpose()?;
+            rotate::prune_after_verify(
+                &bak_dir,
+                store.as_ref(),
+                &bak_parent,
+                &bak_base,
+                &retention,
+                opts.dry_run,
+            )?
+        } else {
+            rotate::prune(&bak_dir, &bak_parent, &bak_base, &retention, opts.dry_run)?
+        };
+        // A dry run already listed them.
+        if !opts.dry_run.is_dry_run() {
+            for pruned in pruned {
+                info!("Pruned old backup: {}", pruned.path.display());
+            }
+        }
+    }
+
+    // Archives written one per directory each have their own ID.
+    let backup_id = match format {
+        _ if !opts.write_manifest || opts.dry_run.is_dry_run() => None,
+        BackupFormat::Dir => Manifest::read_id(&destination)?,
+        BackupFormat::TarGz if !one_archive_per_dir => archive::archive_id(&destination)?,
+        BackupFormat::TarGz => None,
+    };
+    Ok(RunOutcome {
+        destination,
+        stats,
+        backup_id,
+    })
+}
+
+/// Stores the outcome of a backup run in the history database, if one is open.
+fn record_run(
+    history: Option<&History>,
+    kind: &str,
+    source: &Path,
+    started_at: DateTime<Utc>,
+    result: &Result<RunOutcome>,
+) -> Result<()> {
+    let Some(history) = history else {
+        return Ok(());
+    };
+    let duration = (Utc::now() - started_at).to_std().unwrap_or_default();
+    let mut record = RunRecord {
+        id: 0,
+        started_at,
+        kind: kind.to_string(),
+        source: fs::canonicalize(source)
+            .unwrap_or_else(|_| source.to_path_buf())
+            .display()
+            .to_string(),
+        destination: String::new(),
+        status: RunStatus::Failed,
+        files: 0,
+        bytes: 0,
+        errors: 1,
+        duration_ms: duration.as_millis() as u64,
+        error: None,
+        backup_id: None,
+    };
+    match result {
+        Ok(outcome) => {
+            record.destination = outcome.destination.display().to_string();
+            record.status = if outcome.stats.timed_out {
+                RunStatus::Partial
+            } else {
+                RunStatus::Ok
+            };
+            record.backup_id = outcome.backup_id.map(|id| id.to_string());
+            record.files = outcome.stats.files;
+            record.bytes = outcome.stats.bytes;
+            record.errors = 0;
+        }
+        Err(e) => record.error = Some(format!("{e:#}")),
+    }
+    history.record(&record)?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    // Logs go to stderr, so stdout carries only what was asked for.
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let args = Args::parse();
+    let history = args.history_db.as_deref().map(History::open).transpose()?;
+    let started_at = Utc::now();
+
+    match args.command {
+        Commands::File { path, dest, common } => {
+            let result = run_file(&path, dest, &common);
+            let history = history.as_ref().filter(|_| !common.dry_run);
+            record_run(history, "file", &path, started_at, &result)?;
+            result?;
+        }
+        Commands::Dir(dir) => {
+            let source = dir.path.clone();
+            let history = history.as_ref().filter(|_| !dir.common.dry_run);
+            let result = run_dir(*dir);
+            record_run(history, "dir", &source, started_at, &result)?;
+            result?;
+        }
+        Commands::Restore(restore) => restore::run(&restore)?,
+        Commands::Rehydrate(rehydrate) => store::run(&rehydrate)?,
+        Commands::Verify(verify) => verify::run(&verify)?,
+        Commands::List(list) => list::run(&list)?,
+        Commands::Config(ConfigCommand::Generate { command }) => {
+            print!("{}", command.config()?.to_commented_toml());
+        }
+        Commands::Audit(audit) => {
+            let history = history.as_ref().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "audit needs a history database: pass --history-db or set RBAK_HISTORY_DB"
+                )
+            })?;
+            audit::run(&audit, history)?;
+        }
+        Commands::History(args) => {
+            let history = history.as_ref().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "history needs a history database: pass --history-db or set RBAK_HISTORY_DB"
+                )
+            })?;
+            history::run(&args, history)?;
+        }
+    }
+
     Ok(())
 }
 
@@ -150,21 +1833,21 @@ mod tests {
     #[test]
     fn test_backup_path_file() {
         let path = Path::new("Cargo.toml");
-        let bak = backup_path(&path, BackupType::File).unwrap();
+        let bak = backup_path(path, BackupType::File).unwrap();
         assert_eq!(bak.extension().unwrap(), "bak");
     }
 
     #[test]
     fn test_backup_path_directory() {
         let path = Path::new(".git");
-        let bak = backup_path(&path, BackupType::Directory).unwrap();
+        let bak = backup_path(path, BackupType::Directory).unwrap();
         assert!(bak.to_string_lossy().ends_with("_bak"));
     }
 
     #[test]
     fn test_backup_path_invalid_file() {
         let path = Path::new("nonexistent.txt");
-        assert!(backup_path(&path, BackupType::File).is_none());
+        assert!(backup_path(path, BackupType::File).is_none());
     }
 
     #[test]
@@ -256,4 +1939,509 @@ mod tests {
         // Destination directory should exist
         assert!(bak_dir.exists());
     }
+
+    /// Minimal PNG header: signature followed by the start of an IHDR chunk.
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn test_backup_directory_exclude_mime() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("media");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(src_dir.join("photo.png"), PNG_HEADER).unwrap();
+        fs::write(src_dir.join("renamed.txt"), PNG_HEADER).unwrap();
+        fs::write(src_dir.join("notes.txt"), b"plain text").unwrap();
+
+        let dst_dir = tmp.path().join("media_bak");
+        let opts = BackupOptions {
+            exclude_mime: vec!["image/*".to_string()],
+            ..Default::default()
+        };
+        backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert!(!dst_dir.join("photo.png").exists());
+        assert!(!dst_dir.join("renamed.txt").exists());
+        assert!(dst_dir.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_backup_directory_exclude_by_content() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(src_dir.join("secret.txt"), b"# DO NOT BACKUP\nkey=1").unwrap();
+        let mut late_marker = vec![b'x'; 64];
+        late_marker.extend_from_slice(b"DO NOT BACKUP");
+        fs::write(src_dir.join("late.txt"), late_marker).unwrap();
+        fs::write(src_dir.join("plain.txt"), b"hello").unwrap();
+
+        let dst_dir = tmp.path().join("src_bak");
+        let opts = BackupOptions {
+            exclude_content: vec![Regex::new("DO NOT BACKUP").unwrap()],
+            content_check_bytes: 32,
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert_eq!((stats.files, stats.skipped), (2, 1));
+        assert!(!dst_dir.join("secret.txt").exists());
+        // The marker lies beyond the bytes checked.
+        assert!(dst_dir.join("late.txt").exists());
+    }
+
+    #[test]
+    fn test_backup_directory_zero_byte_filters() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir_all(src_dir.join("sub")).unwrap();
+        fs::write(src_dir.join("app.lock"), b"").unwrap();
+        fs::write(src_dir.join("sub/.keep"), b"").unwrap();
+        fs::write(src_dir.join("notes.txt"), b"notes").unwrap();
+
+        let without_empty = tmp.path().join("without_empty");
+        let opts = BackupOptions {
+            zero_byte: ZeroByteFilter::ExcludeEmpty,
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src_dir, &without_empty, &opts).unwrap();
+        assert_eq!((stats.files, stats.skipped), (1, 2));
+        assert!(without_empty.join("notes.txt").exists());
+        assert!(!without_empty.join("app.lock").exists());
+
+        let only_empty = tmp.path().join("only_empty");
+        let opts = BackupOptions {
+            zero_byte: ZeroByteFilter::OnlyEmpty,
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src_dir, &only_empty, &opts).unwrap();
+        assert_eq!((stats.files, stats.skipped), (2, 1));
+        assert!(only_empty.join("sub/.keep").exists());
+        assert!(!only_empty.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_mime_matches() {
+        assert!(mime_matches("image/*", "image/png"));
+        assert!(mime_matches("image/png", "image/png"));
+        assert!(mime_matches("*/*", "application/zip"));
+        assert!(!mime_matches("image/*", "application/zip"));
+        assert!(!mime_matches("image/jpeg", "image/png"));
+    }
+
+    #[test]
+    fn test_backup_directory_dry_run_writes_nothing() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir_all(src_dir.join("nested")).unwrap();
+        fs::write(src_dir.join("nested/test.txt"), b"hello").unwrap();
+
+        let dst_dir = tmp.path().join("src_bak");
+        let opts = BackupOptions {
+            dry_run: DryRunMode::DryRun,
+            ..Default::default()
+        };
+        backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert!(!dst_dir.exists());
+    }
+
+    #[test]
+    fn test_backup_directory_stops_at_deadline() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir(&src_dir).unwrap();
+        for i in 0..5 {
+            fs::write(src_dir.join(format!("file{i}.txt")), b"data").unwrap();
+        }
+
+        let dst_dir = tmp.path().join("src_bak");
+        let opts = BackupOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert!(stats.timed_out);
+        assert_eq!(stats.files, 1);
+        assert_eq!(fs::read_dir(&dst_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("5 weeks").is_err());
+        assert!(parse_duration("m").is_err());
+        assert_eq!(
+            parse_duration("9999999999999999h").unwrap_err(),
+            "duration `9999999999999999h` is too long"
+        );
+        assert!(parse_duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_backup_directory_brotli() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("site");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(src_dir.join("index.html"), b"<html></html>").unwrap();
+
+        let dst_dir = tmp.path().join("site_bak");
+        let opts = BackupOptions {
+            compressor: Some(Compression::Brotli.compressor(None).unwrap()),
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert_eq!(stats.bytes, 13);
+        assert!(dst_dir.join("index.html.br").exists());
+        assert!(!dst_dir.join("index.html").exists());
+    }
+
+    #[test]
+    fn test_canonicalize_dest_relative() {
+        let resolved = canonicalize_dest(Path::new("backups/nested/file.bak")).unwrap();
+        assert!(resolved.is_absolute());
+        assert!(resolved.ends_with("backups/nested/file.bak"));
+        assert_eq!(
+            resolved
+                .parent()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .parent()
+                .unwrap(),
+            fs::canonicalize(".").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_dest_resolves_parent_components() {
+        let resolved = canonicalize_dest(Path::new("nonexistent/../x")).unwrap();
+        assert_eq!(resolved, fs::canonicalize(".").unwrap().join("x"));
+
+        let tmp = TempDir::new().unwrap();
+        let resolved = canonicalize_dest(&tmp.path().join("a/b/../../c/x.bak")).unwrap();
+        assert_eq!(
+            resolved,
+            fs::canonicalize(tmp.path()).unwrap().join("c/x.bak")
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_dest_existing() {
+        let resolved = canonicalize_dest(Path::new("./src/../Cargo.toml")).unwrap();
+        assert_eq!(resolved, fs::canonicalize("Cargo.toml").unwrap());
+    }
+
+    #[test]
+    fn test_backup_directory_rsync_compatible_keeps_mtimes() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        let src_file = src_dir.join("test.txt");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(&src_file, b"hello").unwrap();
+
+        let old = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let times = FileTimes::new().set_accessed(old).set_modified(old);
+        File::options()
+            .write(true)
+            .open(&src_file)
+            .unwrap()
+            .set_times(times)
+            .unwrap();
+
+        let dst_dir = tmp.path().join("src_bak");
+        let opts = BackupOptions {
+            preserve_times: true,
+            preserve_permissions: true,
+            ..Default::default()
+        };
+        backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        let mtime = |p: &Path| fs::metadata(p).unwrap().modified().unwrap();
+        assert_eq!(mtime(&dst_dir.join("test.txt")), old);
+        #[cfg(unix)]
+        assert_eq!(mtime(&dst_dir), mtime(&src_dir));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_directory_rsync_compatible_keeps_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        let src_file = src_dir.join("test.txt");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(&src_file, b"hello").unwrap();
+        fs::set_permissions(&src_file, fs::Permissions::from_mode(0o440)).unwrap();
+        fs::set_permissions(&src_dir, fs::Permissions::from_mode(0o750)).unwrap();
+
+        let dst_dir = tmp.path().join("src_bak");
+        let opts = BackupOptions {
+            preserve_times: true,
+            preserve_permissions: true,
+            ..Default::default()
+        };
+        backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dst_dir.join("test.txt")), 0o440);
+        assert_eq!(mode(&dst_dir), 0o750);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_directory_rsync_compatible_reruns_over_read_only_copies() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        let src_file = src_dir.join("sub/test.txt");
+        fs::create_dir_all(src_file.parent().unwrap()).unwrap();
+        let set_mode = |p: &Path, mode| fs::set_permissions(p, fs::Permissions::from_mode(mode));
+        let write_read_only = |contents: &[u8]| {
+            set_mode(&src_file, 0o640).unwrap();
+            fs::write(&src_file, contents).unwrap();
+            set_mode(&src_file, 0o440).unwrap();
+            set_mode(src_file.parent().unwrap(), 0o550).unwrap();
+        };
+        fs::write(&src_file, b"first").unwrap();
+        write_read_only(b"first");
+
+        let dst_dir = tmp.path().join("src_bak");
+        let opts = BackupOptions {
+            preserve_times: true,
+            preserve_permissions: true,
+            ..Default::default()
+        };
+        backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+        set_mode(src_file.parent().unwrap(), 0o750).unwrap();
+        write_read_only(b"second");
+        backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        let copy = dst_dir.join("sub/test.txt");
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(fs::read(&copy).unwrap(), b"second");
+        assert_eq!((mode(&copy), mode(copy.parent().unwrap())), (0o440, 0o550));
+        for dir in [src_file.parent().unwrap(), copy.parent().unwrap()] {
+            set_mode(dir, 0o750).unwrap();
+        }
+    }
+
+    fn incremental_opts() -> BackupOptions {
+        BackupOptions {
+            write_manifest: true,
+            incremental: true,
+            merge_manifests: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_incremental_backup_merges_manifest() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir_all(src_dir.join("sub")).unwrap();
+        fs::write(src_dir.join("a.txt"), b"one").unwrap();
+        fs::write(src_dir.join("sub/b.txt"), b"two").unwrap();
+
+        let dst_dir = tmp.path().join("src_bak");
+        let first = backup_directory_with(&src_dir, &dst_dir, &incremental_opts()).unwrap();
+        assert_eq!(first.files, 2);
+        let initial = Manifest::load(&dst_dir).unwrap().unwrap();
+
+        fs::write(src_dir.join("a.txt"), b"one, changed").unwrap();
+        fs::write(src_dir.join("c.txt"), b"three").unwrap();
+        fs::remove_file(src_dir.join("sub/b.txt")).unwrap();
+        let second = backup_directory_with(&src_dir, &dst_dir, &incremental_opts()).unwrap();
+        assert_eq!((second.files, second.unchanged), (2, 0));
+
+        // The removed file is still in the backup, so it stays in the manifest.
+        let merged = Manifest::load(&dst_dir).unwrap().unwrap();
+        let keys: Vec<_> = merged.entries.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["a.txt", "c.txt", "sub/b.txt"]);
+        assert_eq!(merged.entries["sub/b.txt"], initial.entries["sub/b.txt"]);
+        assert_eq!(
+            merged.entries["a.txt"].blake3,
+            blake3::hash(b"one, changed").to_hex().as_str()
+        );
+        assert_eq!(merged.entries["c.txt"].size, 5);
+
+        let third = backup_directory_with(&src_dir, &dst_dir, &incremental_opts()).unwrap();
+        assert_eq!((third.files, third.unchanged), (0, 2));
+        assert_eq!(Manifest::load(&dst_dir).unwrap().unwrap(), merged);
+    }
+
+    #[test]
+    fn test_incremental_backup_delete_prunes_manifest() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir(&src_dir).unwrap();
+        fs::write(src_dir.join("keep.txt"), b"keep").unwrap();
+        fs::write(src_dir.join("gone.txt"), b"gone").unwrap();
+
+        let dst_dir = tmp.path().join("src_bak");
+        backup_directory_with(&src_dir, &dst_dir, &incremental_opts()).unwrap();
+
+        fs::remove_file(src_dir.join("gone.txt")).unwrap();
+        let opts = BackupOptions {
+            delete: true,
+            ..incremental_opts()
+        };
+        let stats = backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert_eq!(stats.deleted, 1);
+        assert!(!dst_dir.join("gone.txt").exists());
+        let manifest = Manifest::load(&dst_dir).unwrap().unwrap();
+        assert_eq!(manifest.entries.keys().collect::<Vec<_>>(), ["keep.txt"]);
+    }
+
+    #[test]
+    fn test_incremental_backup_detects_renames() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir_all(src_dir.join("old")).unwrap();
+        fs::write(src_dir.join("old/report.txt"), b"quarterly numbers").unwrap();
+
+        let dst_dir = tmp.path().join("src_bak");
+        backup_directory_with(&src_dir, &dst_dir, &incremental_opts()).unwrap();
+
+        fs::create_dir(src_dir.join("new")).unwrap();
+        fs::rename(
+            src_dir.join("old/report.txt"),
+            src_dir.join("new/renamed.txt"),
+        )
+        .unwrap();
+        let opts = BackupOptions {
+            delete: true,
+            detect_renames: true,
+            ..incremental_opts()
+        };
+        let stats = backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert_eq!((stats.renamed, stats.files, stats.deleted), (1, 0, 0));
+        assert_eq!(stats.bytes, 0);
+        assert!(!dst_dir.join("old/report.txt").exists());
+        assert_eq!(
+            fs::read(dst_dir.join("new/renamed.txt")).unwrap(),
+            b"quarterly numbers"
+        );
+        let manifest = Manifest::load(&dst_dir).unwrap().unwrap();
+        assert_eq!(
+            manifest.entries.keys().collect::<Vec<_>>(),
+            ["new/renamed.txt"]
+        );
+    }
+
+    #[test]
+    fn test_backup_directory_exclude_globs() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("project");
+        fs::create_dir_all(src_dir.join("target/debug")).unwrap();
+        fs::create_dir_all(src_dir.join("src")).unwrap();
+        fs::write(src_dir.join("target/debug/app"), b"bin").unwrap();
+        fs::write(src_dir.join("src/main.rs"), b"fn main() {}").unwrap();
+        fs::write(src_dir.join("src/scratch.tmp"), b"tmp").unwrap();
+
+        let dst_dir = tmp.path().join("project_bak");
+        let opts = BackupOptions {
+            exclude: vec![
+                ExcludePattern::new("target/").unwrap(),
+                ExcludePattern::new("*.tmp").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        assert_eq!((stats.files, stats.skipped), (1, 2));
+        assert!(dst_dir.join("src/main.rs").exists());
+        assert!(!dst_dir.join("src/scratch.tmp").exists());
+        assert!(!dst_dir.join("target").exists());
+    }
+
+    fn parse(args: &[&str]) -> Commands {
+        Args::try_parse_from(args).unwrap().command
+    }
+
+    #[test]
+    fn test_rsync_compatible_rejects_manifest_flags() {
+        for flag in ["--manifest", "--incremental", "--verify-before-prune"] {
+            let args = ["rbak", "dir", "data", "--rsync-compatible", flag];
+            assert!(Args::try_parse_from(args).is_err(), "{flag}");
+        }
+    }
+
+    #[test]
+    fn test_generated_config_drives_dir_backup() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("b.tmp"), b"scratch").unwrap();
+        let src_arg = src.to_str().unwrap();
+        let out = tmp.path().join("out");
+        let config_path = tmp.path().join("rbak.toml");
+
+        let generate = |flags: &[&str]| {
+            let mut args = vec!["rbak", "config", "generate", "dir", src_arg];
+            args.extend(flags);
+            let Commands::Config(ConfigCommand::Generate { command }) = parse(&args) else {
+                unreachable!()
+            };
+            command.config()
+        };
+        let config = generate(&[
+            "--dest",
+            out.to_str().unwrap(),
+            "--compress",
+            "brotli",
+            "--compress-level",
+            "5",
+            "--exclude",
+            "*.tmp",
+            "--manifest",
+        ])
+        .unwrap();
+        fs::write(&config_path, config.to_commented_toml()).unwrap();
+        assert_eq!(Config::from_file(&config_path).unwrap(), config);
+
+        let Commands::Dir(dir) = parse(&[
+            "rbak",
+            "dir",
+            src_arg,
+            "--config",
+            config_path.to_str().unwrap(),
+        ]) else {
+            unreachable!()
+        };
+        let outcome = run_dir(*dir).unwrap();
+        assert_eq!(outcome.destination, out.join("data_bak"));
+        assert_eq!(outcome.stats.files, 1);
+        assert!(out.join("data_bak/a.txt.br").exists());
+        assert!(!out.join("data_bak/b.tmp").exists());
+        assert!(out.join("data_bak").join(manifest::MANIFEST_NAME).exists());
+
+        let err = generate(&["--dry-run", "--max-runtime", "1m"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--dry-run, --max-runtime cannot be written to a config file; pass them on the command line"
+        );
+
+        fs::write(&config_path, "delete = true\n").unwrap();
+        let Commands::Dir(dir) = parse(&[
+            "rbak",
+            "dir",
+            src_arg,
+            "--config",
+            config_path.to_str().unwrap(),
+        ]) else {
+            unreachable!()
+        };
+        let err = run_dir(*dir).err().unwrap();
+        assert_eq!(err.to_string(), "--delete requires --incremental");
+    }
 }
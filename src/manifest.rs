@@ -0,0 +1,210 @@
+use crate::archive::normalize_within_root;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, Metadata},
+    io::{self, BufReader},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use uuid::Uuid;
+
+/// File name of the manifest written at the root of a backup directory.
+pub const MANIFEST_NAME: &str = ".rbak.json";
+
+/// Current manifest format version.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Record of the files held in a backup directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Stable ID of the backup, assigned when it is first written. Manifests
+    /// from before backups had IDs have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    /// Entries keyed by path relative to the backup root, always
+    /// `/`-separated.
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+/// What was known about a source file when it was backed up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub mtime_ns: u64,
+    /// BLAKE3 hash of the uncompressed contents, hex encoded.
+    pub blake3: String,
+    /// Source-relative path, if it differs from the key because a case
+    /// collision on the destination renamed the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            id: None,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+/// Just the ID of a manifest, so finding a backup skips parsing its entries.
+#[derive(Deserialize)]
+struct ManifestId {
+    #[serde(default)]
+    id: Option<Uuid>,
+}
+
+impl Manifest {
+    /// An empty manifest for a new backup, with a fresh ID.
+    pub fn for_new_backup() -> Self {
+        Self {
+            id: Some(Uuid::new_v4()),
+            ..Default::default()
+        }
+    }
+
+    /// Reads only the backup ID from the manifest in `backup_dir`, or `None`
+    /// if it has no manifest or the manifest no ID.
+    pub fn read_id(backup_dir: &Path) -> Result<Option<Uuid>> {
+        let path = backup_dir.join(MANIFEST_NAME);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+        };
+        Self::parse_id(BufReader::new(file)).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Reads only the backup ID from a serialized manifest.
+    pub fn parse_id(reader: impl io::Read) -> Result<Option<Uuid>> {
+        let manifest: ManifestId = serde_json::from_reader(reader)?;
+        Ok(manifest.id)
+    }
+
+    /// Loads the manifest from a backup directory, or `None` if it has none.
+    pub fn load(backup_dir: &Path) -> Result<Option<Self>> {
+        let path = backup_dir.join(MANIFEST_NAME);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+        };
+        let manifest = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Some(manifest))
+    }
+
+    /// Reads a manifest from `path`, which is either the manifest file itself
+    /// or a backup directory holding one.
+    pub fn read(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Self::load(path)?
+                .with_context(|| format!("{} has no {MANIFEST_NAME}", path.display()));
+        }
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Writes the manifest into a backup directory, replacing any existing one.
+    pub fn save(&self, backup_dir: &Path) -> Result<()> {
+        let path = backup_dir.join(MANIFEST_NAME);
+        let json = serde_json::to_vec_pretty(self).context("serializing manifest")?;
+        fs::write(&path, json).with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+impl ManifestEntry {
+    /// Builds an entry for a file with the given BLAKE3 hash (see [`hash_file`]).
+    pub fn with_hash(meta: &Metadata, blake3: String) -> Self {
+        Self {
+            size: meta.len(),
+            mtime_ns: mtime_ns(meta),
+            blake3,
+            source: None,
+        }
+    }
+
+    /// The source-relative path of the file stored under `key`.
+    pub fn source_path<'a>(&'a self, key: &'a str) -> &'a str {
+        self.source.as_deref().unwrap_or(key)
+    }
+
+    /// Returns `true` if size and mtime still match, meaning the file can be
+    /// assumed unchanged without reading it.
+    pub fn matches(&self, meta: &Metadata) -> bool {
+        self.size == meta.len() && self.mtime_ns == mtime_ns(meta)
+    }
+}
+
+/// Modification time in nanoseconds since the epoch, or 0 if unavailable.
+pub fn mtime_ns(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Computes the hex-encoded BLAKE3 hash of a file.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    io::copy(&mut BufReader::new(file), &mut hasher)
+        .with_context(|| format!("hashing {}", path.display()))?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Turns a manifest key back into a path relative to the backup root,
+/// rejecting keys that are absolute, empty or climb out of the root.
+pub fn key_path(key: &str) -> Result<PathBuf> {
+    normalize_within_root(Path::new(""), Path::new(key))
+        .filter(|path| !path.as_os_str().is_empty())
+        .with_context(|| format!("manifest key `{key}` is not a path inside the backup"))
+}
+
+/// Converts a path relative to the backup root into a manifest key.
+pub fn manifest_key(rel: &Path) -> String {
+    rel.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let file = tmp.path().join("a.txt");
+        fs::write(&file, b"hello").unwrap();
+
+        let mut manifest = Manifest::default();
+        let meta = fs::metadata(&file).unwrap();
+        let entry = ManifestEntry::with_hash(&meta, hash_file(&file).unwrap());
+        manifest.entries.insert("a.txt".to_string(), entry);
+        manifest.save(tmp.path()).unwrap();
+
+        assert_eq!(Manifest::load(tmp.path()).unwrap(), Some(manifest));
+        assert_eq!(Manifest::load(&tmp.path().join("missing")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_manifest_key_uses_forward_slashes() {
+        assert_eq!(
+            manifest_key(&Path::new("a").join("b").join("c.txt")),
+            "a/b/c.txt"
+        );
+    }
+}
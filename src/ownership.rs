@@ -0,0 +1,179 @@
+use anyhow::{bail, Context, Result};
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+/// One `OLD:NEW` pair of `--owner-map` or `--group-map`.
+///
+/// `OLD` is the numeric id recorded in the backup; `NEW` is an id or a name
+/// on the system being restored to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdMapping {
+    from: u32,
+    to: String,
+}
+
+impl FromStr for IdMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((from, to)) = s.split_once(':') else {
+            bail!("expected OLD:NEW, got `{s}`");
+        };
+        let from = from
+            .parse()
+            .with_context(|| format!("`{from}` is not a numeric id"))?;
+        if to.is_empty() {
+            bail!("missing the new id in `{s}`");
+        }
+        Ok(Self {
+            from,
+            to: to.to_string(),
+        })
+    }
+}
+
+/// Translates the owner and group of restored files to ids that exist on
+/// this system. Ids not in the map are left to the restoring user.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OwnerMap {
+    users: HashMap<u32, u32>,
+    groups: HashMap<u32, u32>,
+}
+
+impl OwnerMap {
+    /// Builds the map, looking up names in `/etc/passwd` and `/etc/group`.
+    /// Returns `None` when there is nothing to translate.
+    pub fn new(users: &[IdMapping], groups: &[IdMapping]) -> Result<Option<Self>> {
+        if users.is_empty() && groups.is_empty() {
+            return Ok(None);
+        }
+        if cfg!(not(unix)) {
+            bail!("--owner-map and --group-map are only supported on Unix");
+        }
+        Self::with_databases(
+            users,
+            groups,
+            Path::new("/etc/passwd"),
+            Path::new("/etc/group"),
+        )
+        .map(Some)
+    }
+
+    /// Like [`OwnerMap::new`], but looks names up in the given `passwd` and
+    /// `group` files.
+    pub fn with_databases(
+        users: &[IdMapping],
+        groups: &[IdMapping],
+        passwd: &Path,
+        group: &Path,
+    ) -> Result<Self> {
+        Ok(Self {
+            users: resolve(users, passwd)?,
+            groups: resolve(groups, group)?,
+        })
+    }
+
+    /// The new owner and group for a file owned by `uid` and `gid`, each
+    /// `None` when it is not remapped.
+    pub fn translate(&self, uid: u32, gid: u32) -> (Option<u32>, Option<u32>) {
+        (
+            self.users.get(&uid).copied(),
+            self.groups.get(&gid).copied(),
+        )
+    }
+
+    /// Gives `dst` the translated owner and group of `src`.
+    #[cfg(unix)]
+    pub fn apply(&self, src: &Path, dst: &Path) -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let meta = fs::symlink_metadata(src).context("reading source owner")?;
+        self.apply_ids(meta.uid(), meta.gid(), dst)
+    }
+
+    /// Gives `dst` the translated form of the owner `uid` and group `gid`.
+    #[cfg(unix)]
+    pub fn apply_ids(&self, uid: u32, gid: u32, dst: &Path) -> Result<()> {
+        let (uid, gid) = self.translate(uid, gid);
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+        std::os::unix::fs::lchown(dst, uid, gid)
+            .with_context(|| format!("changing owner of {}", dst.display()))
+    }
+
+    /// Other platforms have no Unix owners, and [`OwnerMap::new`] refuses to
+    /// build a map there.
+    #[cfg(not(unix))]
+    pub fn apply(&self, _src: &Path, _dst: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply_ids(&self, _uid: u32, _gid: u32, _dst: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Resolves each mapping's new side, a number or a name in `db` (a file of
+/// `name:password:id:...` lines such as `/etc/passwd`).
+fn resolve(mappings: &[IdMapping], db: &Path) -> Result<HashMap<u32, u32>> {
+    let mut entries: Option<String> = None;
+    let mut ids = HashMap::new();
+    for mapping in mappings {
+        let to = match mapping.to.parse() {
+            Ok(id) => id,
+            Err(_) => {
+                let entries = match &mut entries {
+                    Some(entries) => entries,
+                    None => entries.insert(
+                        fs::read_to_string(db)
+                            .with_context(|| format!("reading {}", db.display()))?,
+                    ),
+                };
+                lookup(entries, &mapping.to).with_context(|| {
+                    format!("no entry named `{}` in {}", mapping.to, db.display())
+                })?
+            }
+        };
+        if ids.insert(mapping.from, to).is_some() {
+            bail!("id {} is mapped more than once", mapping.from);
+        }
+    }
+    Ok(ids)
+}
+
+fn lookup(entries: &str, name: &str) -> Option<u32> {
+    entries.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        (fields.next()? == name).then(|| fields.nth(1)?.parse().ok())?
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_and_resolve_id_mappings() {
+        let tmp = TempDir::new().unwrap();
+        let passwd = tmp.path().join("passwd");
+        fs::write(
+            &passwd,
+            "root:x:0:0::/root:/bin/sh\nalice:x:1001:1001::/home/alice:/bin/sh\n",
+        )
+        .unwrap();
+
+        let mappings: Vec<IdMapping> = ["1000:alice", "500:0"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let ids = resolve(&mappings, &passwd).unwrap();
+        assert_eq!(ids, HashMap::from([(1000, 1001), (500, 0)]));
+
+        assert!("alice:1000".parse::<IdMapping>().is_err());
+        assert!("1000".parse::<IdMapping>().is_err());
+        assert!(resolve(&["1:bob".parse().unwrap()], &passwd).is_err());
+        let twice = ["1:0".parse().unwrap(), "1:1001".parse().unwrap()];
+        assert!(resolve(&twice, &passwd).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use tracing::info;
+
+/// Copies `src` to `dst` by way of `partial`, a file in the `--partial-dir`.
+///
+/// The copy is written to `partial` and only moved to `dst` once complete,
+/// so an interrupted copy leaves its progress in `partial`. If `partial`
+/// already exists and holds a prefix of `src` (checked by hashing both), the
+/// copy resumes after it; otherwise it starts over.
+///
+/// Returns the bytes written by this call, which leaves out a resumed prefix.
+pub fn copy_via_partial(src: &Path, dst: &Path, partial: &Path) -> Result<u64> {
+    let reader = File::open(src).with_context(|| format!("opening {}", src.display()))?;
+    copy_from(reader, src, dst, partial)
+}
+
+fn copy_from(mut reader: impl Read + Seek, src: &Path, dst: &Path, partial: &Path) -> Result<u64> {
+    if let Some(parent) = partial.parent() {
+        fs::create_dir_all(parent).context("creating partial directory")?;
+    }
+    let mut out = File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(partial)
+        .with_context(|| format!("opening {}", partial.display()))?;
+    let have = out.metadata().context("reading partial file")?.len();
+    if have > 0 {
+        if is_prefix(&mut out, &mut reader, have)? {
+            info!("Resuming {} after {have} bytes", src.display());
+        } else {
+            info!("Discarding stale partial copy of {}", src.display());
+            out.set_len(0).context("truncating partial file")?;
+            reader
+                .seek(SeekFrom::Start(0))
+                .context("rewinding source")?;
+        }
+    }
+
+    let mut written = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "reading {} (progress kept in {})",
+                        src.display(),
+                        partial.display()
+                    )
+                })
+            }
+        };
+        out.write_all(&buf[..n])
+            .with_context(|| format!("writing {}", partial.display()))?;
+        written += n as u64;
+    }
+    drop(out);
+    move_into_place(partial, dst)?;
+    Ok(written)
+}
+
+/// Compares the hash of the `len` bytes in `partial` with that of the first
+/// `len` bytes read from `reader`, leaving `reader` just after them.
+fn is_prefix(partial: &mut File, reader: &mut impl Read, len: u64) -> Result<bool> {
+    partial
+        .seek(SeekFrom::Start(0))
+        .context("reading partial file")?;
+    let mut have = blake3::Hasher::new();
+    io::copy(partial, &mut have).context("hashing partial file")?;
+    let mut want = blake3::Hasher::new();
+    let read = io::copy(&mut reader.take(len), &mut want).context("hashing source prefix")?;
+    Ok(read == len && have.finalize() == want.finalize())
+}
+
+/// Renames the finished copy to `dst`, copying across filesystems if needed.
+fn move_into_place(partial: &Path, dst: &Path) -> Result<()> {
+    if fs::rename(partial, dst).is_ok() {
+        return Ok(());
+    }
+    fs::copy(partial, dst).with_context(|| format!("moving finished copy to {}", dst.display()))?;
+    fs::remove_file(partial).with_context(|| format!("removing {}", partial.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_file, BackupOptions};
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    /// A source that fails once `limit` bytes have been read, like a copy
+    /// cut off by a dying disk or network share.
+    struct Interrupted {
+        inner: Cursor<Vec<u8>>,
+        limit: u64,
+    }
+
+    impl Read for Interrupted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let left = self.limit.saturating_sub(self.inner.position());
+            if left == 0 {
+                return Err(io::Error::other("connection reset"));
+            }
+            let n = buf.len().min(left as usize);
+            self.inner.read(&mut buf[..n])
+        }
+    }
+
+    impl Seek for Interrupted {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_interrupted_copy_resumes_from_partial_dir() {
+        let tmp = TempDir::new().unwrap();
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let src = tmp.path().join("big.bin");
+        fs::write(&src, &contents).unwrap();
+        let dst = tmp.path().join("big.bak");
+        let partial = tmp.path().join("partials/big.bin");
+
+        let source = Interrupted {
+            inner: Cursor::new(contents.clone()),
+            limit: 70_000,
+        };
+        let err = copy_from(source, &src, &dst, &partial).unwrap_err();
+        assert!(format!("{err:#}").contains("connection reset"));
+        assert!(!dst.exists());
+        assert_eq!(fs::read(&partial).unwrap(), &contents[..70_000]);
+
+        // The next run picks the partial up from the --partial-dir.
+        let opts = BackupOptions {
+            partial_dir: Some(tmp.path().join("partials")),
+            ..Default::default()
+        };
+        assert_eq!(backup_file(&src, &dst, &opts).unwrap(), 130_000);
+        assert_eq!(fs::read(&dst).unwrap(), contents);
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn test_stale_partial_is_discarded() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("file.txt");
+        fs::write(&src, b"fresh contents").unwrap();
+        let dst = tmp.path().join("file.bak");
+        let partial = tmp.path().join("partials/file.txt");
+        fs::create_dir_all(partial.parent().unwrap()).unwrap();
+        fs::write(&partial, b"stale").unwrap();
+
+        assert_eq!(copy_via_partial(&src, &dst, &partial).unwrap(), 14);
+        assert_eq!(fs::read(&dst).unwrap(), b"fresh contents");
+    }
+}
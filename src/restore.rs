@@ -0,0 +1,650 @@
+use crate::{
+    archive::{extract_archive, ExtractOptions, TAR_GZ_EXTENSION},
+    backup_directory_with, backup_file,
+    compress::Compression,
+    filter::ExcludePattern,
+    list::{find_by_id, search_dirs},
+    manifest::{key_path, Manifest, MANIFEST_NAME},
+    ownership::{IdMapping, OwnerMap},
+    rotate::split_timestamp,
+    summary::SummaryFormat,
+    verify::locate,
+    BackupOptions, BackupStats, DryRunMode,
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tracing::info;
+use uuid::Uuid;
+
+/// Suffix of the copy `--backup-existing` makes of the restore target.
+const PRE_RESTORE_SUFFIX: &str = ".pre-restore";
+
+/// Arguments of `rbak restore`.
+#[derive(Debug, clap::Args)]
+pub struct RestoreArgs {
+    /// Backup to restore: a `.bak` file, a `_bak` directory or a `.tar.gz` archive
+    #[arg(required_unless_present = "id")]
+    backup: Option<PathBuf>,
+    /// Restore the backup with this ID instead, looking for it in the --search directories
+    #[arg(long, value_name = "UUID", conflicts_with = "backup")]
+    id: Option<Uuid>,
+    /// Directory of backups to search for --id (default: the current directory)
+    #[arg(long, value_name = "DIR", requires = "id")]
+    search: Vec<PathBuf>,
+    /// Also search the `dest` of this config file for --id (repeatable)
+    #[arg(long, value_name = "FILE", requires = "id")]
+    config: Vec<PathBuf>,
+    /// Path to restore to (default: the original name next to the backup)
+    #[arg(short, long)]
+    dest: Option<PathBuf>,
+    /// Restore a directory or archive as DEST/<original name> rather than into DEST itself
+    #[arg(long, requires = "dest")]
+    preserve_top_dir: bool,
+    /// Give files owned by uid OLD in the backup to user NEW (a uid or name), e.g. 1000:alice
+    #[arg(long, value_name = "OLD:NEW", value_delimiter = ',')]
+    owner_map: Vec<IdMapping>,
+    /// Give files in gid OLD in the backup to group NEW (a gid or name)
+    #[arg(long, value_name = "OLD:NEW", value_delimiter = ',')]
+    group_map: Vec<IdMapping>,
+    /// First copy whatever is at the restore path to <path>.pre-restore
+    #[arg(long)]
+    backup_existing: bool,
+    /// Extract archive entries even if they resolve outside the destination
+    #[arg(long)]
+    allow_escape: bool,
+    /// Print what would be done without touching the filesystem
+    #[arg(short = 'n', long, alias = "no-action")]
+    dry_run: bool,
+    /// Print an end-of-run summary in the given format
+    #[arg(long, value_name = "FORMAT")]
+    summary_format: Option<SummaryFormat>,
+}
+
+/// The shapes of backup rbak can restore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupKind {
+    File,
+    Directory,
+    Archive,
+}
+
+impl BackupKind {
+    pub fn detect(backup: &Path) -> Result<Self> {
+        if backup
+            .to_string_lossy()
+            .ends_with(&format!(".{TAR_GZ_EXTENSION}"))
+        {
+            return Ok(BackupKind::Archive);
+        }
+        let meta =
+            fs::metadata(backup).with_context(|| format!("reading backup {}", backup.display()))?;
+        if meta.is_dir() {
+            Ok(BackupKind::Directory)
+        } else {
+            Ok(BackupKind::File)
+        }
+    }
+}
+
+/// Works out where a directory backup or archive came from:
+/// `data_bak`, `data_bak_20261015T120000Z` and `data_bak.tar.gz` all map to `data`.
+///
+/// File backups return `None`, since `.bak` replaced the original extension.
+pub fn original_path(backup: &Path, kind: BackupKind) -> Option<PathBuf> {
+    let name = backup.file_name()?.to_str()?;
+    let name = match kind {
+        BackupKind::File => return None,
+        BackupKind::Directory => name,
+        BackupKind::Archive => name.strip_suffix(TAR_GZ_EXTENSION)?.strip_suffix('.')?,
+    };
+    let name = split_timestamp(name).map_or(name, |(base, _)| base);
+    let original = name.strip_suffix("_bak")?;
+    Some(backup.with_file_name(original))
+}
+
+/// Works out where `backup` is restored to.
+///
+/// Without `dest` that is its original path. A directory or archive is
+/// otherwise restored into `dest` itself, or with `preserve_top_dir` into a
+/// directory under `dest` named like the original.
+pub fn restore_target(
+    backup: &Path,
+    kind: BackupKind,
+    dest: Option<&Path>,
+    preserve_top_dir: bool,
+) -> Result<PathBuf> {
+    let original = original_path(backup, kind);
+    Ok(match (dest, original) {
+        (Some(dest), Some(original)) if preserve_top_dir => {
+            dest.join(original.file_name().unwrap_or_default())
+        }
+        (Some(_), None) if preserve_top_dir => bail!(
+            "--preserve-top-dir needs a directory or archive backup with a `_bak` name, not {}",
+            backup.display()
+        ),
+        (Some(dest), _) => dest.to_path_buf(),
+        (None, Some(original)) => original,
+        (None, None) => bail!(
+            "cannot tell where {} came from; pass --dest",
+            backup.display()
+        ),
+    })
+}
+
+/// Restores `backup` to `target`, returning what was copied.
+///
+/// Directory backups go through the same traversal as backups, minus their
+/// manifest. Symlinks are never followed or recreated when copying, so only
+/// archives need the escape checks done by [`extract_archive`]. Compressed
+/// copies are decompressed under their original names.
+pub fn restore(
+    backup: &Path,
+    target: &Path,
+    kind: BackupKind,
+    opts: ExtractOptions,
+) -> Result<BackupStats> {
+    let started = Instant::now();
+    let copy_opts = BackupOptions {
+        dry_run: opts.dry_run,
+        exclude: vec![ExcludePattern::new(&format!("/{MANIFEST_NAME}"))?],
+        owners: opts.owners.clone(),
+        ..Default::default()
+    };
+    let mut stats = match kind {
+        BackupKind::File => BackupStats {
+            files: 1,
+            bytes: match file_compression(backup) {
+                Some(algo) if copy_opts.dry_run.would_copy(backup, target) => {
+                    decompress_file(backup, target, algo, &copy_opts)?
+                }
+                Some(_) => 0,
+                None => backup_file(backup, target, &copy_opts)?,
+            },
+            ..Default::default()
+        },
+        BackupKind::Directory => {
+            ensure_not_store_backup(backup)?;
+            let mut stats = backup_directory_with(backup, target, &copy_opts)?;
+            // The manifest is rbak's own bookkeeping, not a skipped user file.
+            if backup.join(MANIFEST_NAME).is_file() {
+                stats.skipped -= 1;
+            }
+            if !copy_opts.dry_run.is_dry_run() {
+                decompress_restored(backup, target, &copy_opts, &mut stats)?;
+            }
+            stats
+        }
+        BackupKind::Archive => extract_archive(backup, target, opts)?,
+    };
+    stats.duration = started.elapsed();
+    Ok(stats)
+}
+
+/// Fails for a directory backup whose manifest lists files of which none
+/// are in the directory, as with `dir --store` backups, which only
+/// `rbak rehydrate` can restore.
+fn ensure_not_store_backup(backup: &Path) -> Result<()> {
+    let Some(manifest) = Manifest::load(backup)? else {
+        return Ok(());
+    };
+    let store_only = !manifest.entries.is_empty()
+        && manifest
+            .entries
+            .iter()
+            .all(|(key, entry)| locate(backup, key, &entry.blake3, None).is_none());
+    if store_only {
+        bail!(
+            "{} is a --store backup holding only its manifest; rebuild it with \
+             `rbak rehydrate <store> {} --dest <dir>`",
+            backup.display(),
+            backup.display()
+        );
+    }
+    Ok(())
+}
+
+/// The algorithm a file backup was compressed with, going by its
+/// `.bak.<ext>` name.
+fn file_compression(backup: &Path) -> Option<Compression> {
+    let name = backup.file_name()?.to_str()?;
+    Compression::value_variants().iter().copied().find(|algo| {
+        algo.compressor(None)
+            .is_ok_and(|c| name.ends_with(&format!(".bak.{}", c.extension())))
+    })
+}
+
+/// Writes the decompressed contents of `compressed` to `dst`, returning the
+/// bytes written.
+fn decompress_file(
+    compressed: &Path,
+    dst: &Path,
+    algo: Compression,
+    opts: &BackupOptions,
+) -> Result<u64> {
+    let file =
+        File::open(compressed).with_context(|| format!("opening {}", compressed.display()))?;
+    let out = File::create(dst).with_context(|| format!("creating {}", dst.display()))?;
+    let mut writer = BufWriter::new(out);
+    let written = algo
+        .compressor(None)?
+        .decompress(&mut BufReader::new(file), &mut writer)
+        .with_context(|| format!("decompressing {}", compressed.display()))?;
+    writer
+        .flush()
+        .with_context(|| format!("writing {}", dst.display()))?;
+    if let Some(owners) = &opts.owners {
+        owners.apply(compressed, dst)?;
+    }
+    Ok(written)
+}
+
+/// Replaces the compressed copies a directory restore brought back with
+/// their contents under the original names, finding them through the
+/// backup's manifest as `rbak verify` does.
+fn decompress_restored(
+    backup: &Path,
+    target: &Path,
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+) -> Result<()> {
+    let Some(manifest) = Manifest::load(backup)? else {
+        return Ok(());
+    };
+    for (key, entry) in &manifest.entries {
+        let Some((copy, Some(algo))) = locate(backup, key, &entry.blake3, None) else {
+            continue;
+        };
+        let compressed = fs::metadata(&copy)
+            .with_context(|| format!("reading {}", copy.display()))?
+            .len();
+        let written = decompress_file(&copy, &target.join(key_path(key)?), algo, opts)?;
+        // The tree copy brought the compressed file along; only its contents are wanted.
+        let restored = target.join(copy.strip_prefix(backup).unwrap_or(&copy));
+        fs::remove_file(&restored).with_context(|| format!("removing {}", restored.display()))?;
+        stats.bytes = stats.bytes.saturating_sub(compressed) + written;
+    }
+    Ok(())
+}
+
+/// Copies whatever is at `target` to `<target>.pre-restore`, so a restore
+/// over it can be undone. Returns where the copy went, or `None` if there was
+/// nothing to displace.
+pub fn backup_existing(target: &Path, dry_run: DryRunMode) -> Result<Option<PathBuf>> {
+    let meta = match fs::symlink_metadata(target) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", target.display())),
+    };
+    let Some(name) = target.file_name() else {
+        bail!("cannot name a pre-restore copy of {}", target.display());
+    };
+    let mut name = name.to_os_string();
+    name.push(PRE_RESTORE_SUFFIX);
+    let saved = target.with_file_name(name);
+    if saved.exists() {
+        bail!(
+            "{} already exists; move it away before restoring with --backup-existing",
+            saved.display()
+        );
+    }
+
+    let opts = BackupOptions {
+        dry_run,
+        preserve_times: true,
+        preserve_permissions: true,
+        ..Default::default()
+    };
+    if meta.is_dir() {
+        backup_directory_with(target, &saved, &opts)?;
+    } else {
+        backup_file(target, &saved, &opts)?;
+    }
+    Ok(Some(saved))
+}
+
+/// Runs `rbak restore`.
+pub fn run(args: &RestoreArgs) -> Result<()> {
+    let backup = match (&args.backup, args.id) {
+        (Some(backup), _) => backup.clone(),
+        (None, Some(id)) => find_by_id(id, &search_dirs(&args.search, &args.config)?)?,
+        (None, None) => bail!("pass a backup to restore or --id"),
+    };
+    let kind = BackupKind::detect(&backup)?;
+    let target = restore_target(&backup, kind, args.dest.as_deref(), args.preserve_top_dir)?;
+
+    if kind == BackupKind::Directory {
+        // Before --backup-existing moves anything.
+        ensure_not_store_backup(&backup)?;
+    }
+
+    let dry_run = DryRunMode::from_flag(args.dry_run);
+    if args.backup_existing {
+        let saved = backup_existing(&target, dry_run).context("saving existing files")?;
+        if let Some(saved) = saved {
+            let verb = if dry_run.is_dry_run() {
+                "Would save"
+            } else {
+                "Saved"
+            };
+            info!(
+                "{verb} existing {} to {}",
+                target.display(),
+                saved.display()
+            );
+        }
+    }
+
+    info!("Restoring {} to {}", backup.display(), target.display());
+    let opts = ExtractOptions {
+        allow_escape: args.allow_escape,
+        dry_run,
+        owners: OwnerMap::new(&args.owner_map, &args.group_map)?,
+    };
+    let stats = restore(&backup, &target, kind, opts).context("restoring backup")?;
+    match args.summary_format {
+        Some(format) => println!("{}", format.render_as("Restore", &stats)),
+        None => info!("Restored {}", target.display()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory, manifest::Manifest};
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_original_path() {
+        let dir = |name: &str| original_path(Path::new(name), BackupKind::Directory);
+        assert_eq!(dir("/b/data_bak"), Some(PathBuf::from("/b/data")));
+        assert_eq!(
+            dir("/b/data_bak_20261015T120000Z"),
+            Some(PathBuf::from("/b/data"))
+        );
+        assert_eq!(dir("/b/data"), None);
+        assert_eq!(
+            original_path(Path::new("data_bak.tar.gz"), BackupKind::Archive),
+            Some(PathBuf::from("data"))
+        );
+        assert_eq!(original_path(Path::new("a.bak"), BackupKind::File), None);
+    }
+
+    #[test]
+    fn test_restore_directory_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), b"hello").unwrap();
+        let bak = tmp.path().join("data_bak");
+        backup_directory(&src, &bak).unwrap();
+        Manifest::default().save(&bak).unwrap();
+
+        let restored = tmp.path().join("restored");
+        restore(
+            &bak,
+            &restored,
+            BackupKind::Directory,
+            ExtractOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(fs::read(restored.join("sub/a.txt")).unwrap(), b"hello");
+        assert!(!restored.join(MANIFEST_NAME).exists());
+    }
+
+    #[test]
+    fn test_restore_compressed_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "alpha ".repeat(100)).unwrap();
+        fs::write(src.join("sub/b.txt"), b"beta").unwrap();
+        let compressed = || BackupOptions {
+            compressor: Some(Compression::Brotli.compressor(Some(5)).unwrap()),
+            ..Default::default()
+        };
+        let bak = tmp.path().join("data_bak");
+        backup_directory_with(&src, &bak, &compressed()).unwrap();
+        assert!(bak.join("sub/b.txt.br").is_file());
+
+        let restored = tmp.path().join("restored");
+        let opts = ExtractOptions::default();
+        let stats = restore(&bak, &restored, BackupKind::Directory, opts).unwrap();
+        for rel in ["a.txt", "sub/b.txt"] {
+            assert_eq!(
+                fs::read(restored.join(rel)).unwrap(),
+                fs::read(src.join(rel)).unwrap()
+            );
+            assert!(!restored.join(format!("{rel}.br")).exists());
+        }
+        assert_eq!((stats.files, stats.bytes), (2, 604));
+
+        let file_bak = tmp.path().join("a.bak.br");
+        backup_file(&src.join("a.txt"), &file_bak, &compressed()).unwrap();
+        let file = tmp.path().join("a.txt");
+        restore(
+            &file_bak,
+            &file,
+            BackupKind::File,
+            ExtractOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read(&file).unwrap(),
+            fs::read(src.join("a.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_restore_refuses_store_backups() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        let bak = tmp.path().join("data_bak");
+        let opts = BackupOptions {
+            store: Some(tmp.path().join("store")),
+            write_manifest: true,
+            ..Default::default()
+        };
+        backup_directory_with(&src, &bak, &opts).unwrap();
+
+        let restored = tmp.path().join("restored");
+        let opts = ExtractOptions::default();
+        let err = restore(&bak, &restored, BackupKind::Directory, opts).unwrap_err();
+        assert!(err.to_string().contains("rbak rehydrate"), "{err}");
+        assert!(!restored.exists());
+    }
+
+    #[test]
+    fn test_restore_target_preserve_top_dir() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        let bak = tmp.path().join("data_bak_20261015T120000Z");
+        backup_directory(&src, &bak).unwrap();
+        let dest = tmp.path().join("dest");
+        let kind = BackupKind::Directory;
+
+        let target = restore_target(&bak, kind, Some(&dest), true).unwrap();
+        assert_eq!(target, dest.join("data"));
+        restore(&bak, &target, kind, ExtractOptions::default()).unwrap();
+        assert_eq!(fs::read(dest.join("data/a.txt")).unwrap(), b"hello");
+
+        let file_bak = tmp.path().join("a.bak");
+        fs::write(&file_bak, b"hello").unwrap();
+        assert!(restore_target(&file_bak, BackupKind::File, Some(&dest), true).is_err());
+    }
+
+    #[test]
+    fn test_restore_target_no_target_dir() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        let bak = tmp.path().join("data_bak");
+        backup_directory(&src, &bak).unwrap();
+        let dest = tmp.path().join("dest");
+        let kind = BackupKind::Directory;
+
+        let target = restore_target(&bak, kind, Some(&dest), false).unwrap();
+        assert_eq!(target, dest);
+        restore(&bak, &target, kind, ExtractOptions::default()).unwrap();
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), b"hello");
+        assert!(!dest.join("data").exists());
+
+        assert_eq!(restore_target(&bak, kind, None, false).unwrap(), src);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "giving files away needs root; run with `cargo test -- --ignored` as root"]
+    fn test_restore_translates_owners() {
+        use crate::archive::archive_directory;
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        let meta = fs::metadata(src.join("a.txt")).unwrap();
+        let bak = tmp.path().join("data_bak");
+        backup_directory(&src, &bak).unwrap();
+        let (archive, _) = archive_directory(
+            &src,
+            &tmp.path().join("data_bak"),
+            &BackupOptions::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(archive.extension().unwrap(), "gz");
+
+        let owners = OwnerMap::new(
+            &[format!("{}:4242", meta.uid()).parse().unwrap()],
+            &[format!("{}:4343", meta.gid()).parse().unwrap()],
+        )
+        .unwrap();
+        for (backup, kind) in [
+            (&bak, BackupKind::Directory),
+            (&archive, BackupKind::Archive),
+        ] {
+            let restored = tmp.path().join(format!("restored_{kind:?}"));
+            let opts = ExtractOptions {
+                owners: owners.clone(),
+                ..Default::default()
+            };
+            restore(backup, &restored, kind, opts).unwrap();
+            let meta = fs::metadata(restored.join("a.txt")).unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (4242, 4343), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn test_backup_existing_keeps_displaced_files() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), b"good").unwrap();
+        let bak = tmp.path().join("data_bak");
+        backup_directory(&src, &bak).unwrap();
+        fs::write(src.join("sub/a.txt"), b"edited since").unwrap();
+
+        let saved = backup_existing(&src, DryRunMode::Apply).unwrap().unwrap();
+        assert_eq!(saved, tmp.path().join("data.pre-restore"));
+        restore(&bak, &src, BackupKind::Directory, ExtractOptions::default()).unwrap();
+
+        assert_eq!(fs::read(src.join("sub/a.txt")).unwrap(), b"good");
+        assert_eq!(fs::read(saved.join("sub/a.txt")).unwrap(), b"edited since");
+        let err = backup_existing(&src, DryRunMode::Apply).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        assert_eq!(
+            backup_existing(&tmp.path().join("missing"), DryRunMode::Apply).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_owner_map_arguments_resolve_names() {
+        use clap::Parser;
+
+        let args = crate::Args::try_parse_from([
+            "rbak",
+            "restore",
+            "data_bak",
+            "--owner-map",
+            "1000:alice,500:0",
+            "--group-map",
+            "100:staff",
+        ])
+        .unwrap();
+        let crate::Commands::Restore(args) = args.command else {
+            panic!("expected a restore command");
+        };
+        assert_eq!(args.owner_map.len(), 2);
+
+        let tmp = TempDir::new().unwrap();
+        let (passwd, group) = (tmp.path().join("passwd"), tmp.path().join("group"));
+        fs::write(
+            &passwd,
+            "root:x:0:0::/root:/bin/sh\nalice:x:1001:1001::/home/alice:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(&group, "root:x:0:\nstaff:x:50:alice\n").unwrap();
+        let owners =
+            OwnerMap::with_databases(&args.owner_map, &args.group_map, &passwd, &group).unwrap();
+        assert_eq!(owners.translate(1000, 100), (Some(1001), Some(50)));
+        assert_eq!(owners.translate(500, 7), (Some(0), None));
+        assert_eq!(owners.translate(42, 42), (None, None));
+
+        let unknown =
+            crate::Args::try_parse_from(["rbak", "restore", "b", "--owner-map", "1:bob"]).unwrap();
+        let crate::Commands::Restore(unknown) = unknown.command else {
+            panic!("expected a restore command");
+        };
+        let err = OwnerMap::with_databases(&unknown.owner_map, &[], &passwd, &group).unwrap_err();
+        assert!(err.to_string().contains("no entry named `bob`"));
+        assert!(
+            crate::Args::try_parse_from(["rbak", "restore", "b", "--owner-map", "alice:1"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_restore_stats_match_backup_stats() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub/deeper")).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        fs::write(src.join("sub/b.txt"), b"world!").unwrap();
+        fs::write(src.join("sub/deeper/c.txt"), b"!").unwrap();
+        let bak = tmp.path().join("data_bak");
+        let backup_opts = BackupOptions {
+            write_manifest: true,
+            ..Default::default()
+        };
+        let backed_up = backup_directory_with(&src, &bak, &backup_opts).unwrap();
+
+        let restored = restore(
+            &bak,
+            &tmp.path().join("restored"),
+            BackupKind::Directory,
+            ExtractOptions::default(),
+        )
+        .unwrap();
+
+        let without_duration = |stats: BackupStats| BackupStats {
+            duration: Duration::ZERO,
+            ..stats
+        };
+        assert_eq!(without_duration(restored), without_duration(backed_up));
+    }
+}
@@ -0,0 +1,467 @@
+use crate::{store::ObjectStore, verify::describe_damage, DryRunMode};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// UTC timestamp format used in rotated backup names (`data_bak_20261015T120000Z`).
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// A timestamped backup found next to others of the same source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimestampedBackup {
+    pub path: PathBuf,
+    pub created: DateTime<Utc>,
+}
+
+impl TimestampedBackup {
+    /// Total size of the files in the backup, without following symlinks.
+    pub fn size(&self) -> Result<u64> {
+        disk_size(&self.path)
+    }
+}
+
+fn disk_size(path: &Path) -> Result<u64> {
+    let meta = fs::symlink_metadata(path).with_context(|| format!("reading {}", path.display()))?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path).with_context(|| format!("listing {}", path.display()))? {
+        size += disk_size(&entry.context("reading directory entry")?.path())?;
+    }
+    Ok(size)
+}
+
+/// Appends a creation timestamp to a backup name: `data_bak` → `data_bak_20261015T120000Z`.
+pub fn timestamped_name(base: &str, created: DateTime<Utc>) -> String {
+    format!("{base}_{}", created.format(TIMESTAMP_FORMAT))
+}
+
+/// Parses the timestamp out of a name produced by [`timestamped_name`].
+fn parse_timestamped_name(base: &str, name: &str) -> Option<DateTime<Utc>> {
+    split_timestamp(name)
+        .filter(|(b, _)| *b == base)
+        .map(|(_, t)| t)
+}
+
+/// Splits `data_bak_20261015T120000Z` into `data_bak` and its timestamp.
+pub fn split_timestamp(name: &str) -> Option<(&str, DateTime<Utc>)> {
+    let (base, stamp) = name.rsplit_once('_')?;
+    let created = NaiveDateTime::parse_from_str(stamp, TIMESTAMP_FORMAT).ok()?;
+    Some((base, created.and_utc()))
+}
+
+/// Lists the timestamped backups named after `base` inside `dir`, newest first.
+pub fn list_backups(dir: &Path, base: &str) -> Result<Vec<TimestampedBackup>> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("listing {}", dir.display()))? {
+        let entry = entry.context("reading directory entry")?;
+        let name = entry.file_name();
+        if let Some(created) = parse_timestamped_name(base, &name.to_string_lossy()) {
+            backups.push(TimestampedBackup {
+                path: entry.path(),
+                created,
+            });
+        }
+    }
+    backups.sort_by_key(|b| std::cmp::Reverse(b.created));
+    Ok(backups)
+}
+
+/// Which timestamped backups survive pruning.
+///
+/// A backup is kept if any rule keeps it: the newest `keep_last` overall, or
+/// the newest backup of each of the `daily` (`weekly`, `monthly`) most recent
+/// days (ISO weeks, months) that have one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+impl RetentionPolicy {
+    /// Returns `true` if no rule is set, meaning backups are not rotated.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Splits `backups`, newest first, into the kept and the expired ones.
+    pub fn apply(
+        &self,
+        backups: Vec<TimestampedBackup>,
+    ) -> (Vec<TimestampedBackup>, Vec<TimestampedBackup>) {
+        let mut rules = [
+            Bucketed::new(self.daily, |t| (t.year(), t.ordinal() as i32)),
+            Bucketed::new(self.weekly, |t| {
+                let week = t.iso_week();
+                (week.year(), week.week() as i32)
+            }),
+            Bucketed::new(self.monthly, |t| (t.year(), t.month() as i32)),
+        ];
+        let (mut kept, mut expired) = (Vec::new(), Vec::new());
+        for (i, backup) in backups.into_iter().enumerate() {
+            // Every rule sees every backup, so each tracks its own buckets.
+            let mut keep = i < self.keep_last;
+            for rule in &mut rules {
+                keep |= rule.keeps(backup.created);
+            }
+            if keep {
+                kept.push(backup);
+            } else {
+                expired.push(backup);
+            }
+        }
+        (kept, expired)
+    }
+}
+
+/// One GFS rule: keeps the first (newest) backup seen in each of up to
+/// `remaining` buckets.
+struct Bucketed {
+    remaining: usize,
+    bucket: fn(DateTime<Utc>) -> (i32, i32),
+    last: Option<(i32, i32)>,
+}
+
+impl Bucketed {
+    fn new(remaining: usize, bucket: fn(DateTime<Utc>) -> (i32, i32)) -> Self {
+        Self {
+            remaining,
+            bucket,
+            last: None,
+        }
+    }
+
+    fn keeps(&mut self, created: DateTime<Utc>) -> bool {
+        let bucket = (self.bucket)(created);
+        if self.remaining == 0 || self.last == Some(bucket) {
+            return false;
+        }
+        self.last = Some(bucket);
+        self.remaining -= 1;
+        true
+    }
+}
+
+/// Deletes the backups named after `base` in `dir` that `policy` does not
+/// keep, counting the just-made `new_backup` among them.
+///
+/// Returns the backups that were (or, on a dry run, would be) deleted. A dry
+/// run never wrote `new_backup`, so it is counted from its name to keep the
+/// same backups a real run would; each backup it would delete is listed with
+/// its size and creation time.
+pub fn prune(
+    new_backup: &Path,
+    dir: &Path,
+    base: &str,
+    policy: &RetentionPolicy,
+    dry_run: DryRunMode,
+) -> Result<Vec<TimestampedBackup>> {
+    let mut backups = if dir.is_dir() {
+        list_backups(dir, base)?
+    } else {
+        Vec::new()
+    };
+    let new_name = new_backup.file_name().unwrap_or_default();
+    if let Some(created) = parse_timestamped_name(base, &new_name.to_string_lossy()) {
+        if !backups.iter().any(|b| b.path.file_name() == Some(new_name)) {
+            backups.push(TimestampedBackup {
+                path: new_backup.to_path_buf(),
+                created,
+            });
+            backups.sort_by_key(|b| std::cmp::Reverse(b.created));
+        }
+    }
+
+    let (_, expired) = policy.apply(backups);
+    for backup in &expired {
+        if dry_run.would_prune(backup)? {
+            fs::remove_dir_all(&backup.path)
+                .with_context(|| format!("deleting {}", backup.path.display()))?;
+        }
+    }
+    Ok(expired)
+}
+
+/// Like [`prune`], but first re-hashes `new_backup` against its manifest and
+/// deletes nothing if it is missing, unverifiable or damaged.
+///
+/// On a dry run the new backup was never written, so it is not checked.
+pub fn prune_after_verify(
+    new_backup: &Path,
+    store: Option<&ObjectStore>,
+    dir: &Path,
+    base: &str,
+    policy: &RetentionPolicy,
+    dry_run: DryRunMode,
+) -> Result<Vec<TimestampedBackup>> {
+    if !dry_run.is_dry_run() {
+        if let Some(problem) = describe_damage(new_backup, store)? {
+            bail!(
+                "new backup {} failed verification: {problem}; no older backups were pruned",
+                new_backup.display()
+            );
+        }
+    }
+    prune(new_backup, dir, base, policy, dry_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, day, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_timestamped_name_round_trip() {
+        let name = timestamped_name("data_bak", at(15));
+        assert_eq!(name, "data_bak_20261015T120000Z");
+        assert_eq!(parse_timestamped_name("data_bak", &name), Some(at(15)));
+        assert_eq!(parse_timestamped_name("data_bak", "data_bak"), None);
+        assert_eq!(parse_timestamped_name("data", &name), None);
+    }
+
+    #[test]
+    fn test_prune_keeps_newest() {
+        let tmp = TempDir::new().unwrap();
+        for day in 1..=4 {
+            fs::create_dir(tmp.path().join(timestamped_name("data_bak", at(day)))).unwrap();
+        }
+        fs::create_dir(tmp.path().join("other_bak_20261001T120000Z")).unwrap();
+
+        let policy = RetentionPolicy {
+            keep_last: 2,
+            ..Default::default()
+        };
+        let newest = tmp.path().join(timestamped_name("data_bak", at(4)));
+        let deleted = prune(&newest, tmp.path(), "data_bak", &policy, DryRunMode::Apply).unwrap();
+
+        let deleted: Vec<_> = deleted.iter().map(|b| b.created).collect();
+        assert_eq!(deleted, [at(2), at(1)]);
+        let left: Vec<_> = list_backups(tmp.path(), "data_bak")
+            .unwrap()
+            .iter()
+            .map(|b| b.created)
+            .collect();
+        assert_eq!(left, [at(4), at(3)]);
+        assert!(tmp.path().join("other_bak_20261001T120000Z").exists());
+    }
+
+    #[test]
+    fn test_dry_run_prune_matches_real_prune() {
+        let day = |m: u32, d: u32| Utc.with_ymd_and_hms(2026, m, d, 12, 0, 0).unwrap();
+        // A backup every three days from 2026-08-01; the new one is 2026-10-15.
+        let times: Vec<_> = (0..25)
+            .map(|i| day(8, 1) + chrono::Duration::days(3 * i))
+            .collect();
+        let new = day(10, 15);
+        let names = |backups: &[TimestampedBackup]| -> Vec<String> {
+            backups
+                .iter()
+                .map(|b| b.path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let policies = [
+            RetentionPolicy {
+                keep_last: 3,
+                ..Default::default()
+            },
+            RetentionPolicy {
+                daily: 4,
+                ..Default::default()
+            },
+            RetentionPolicy {
+                weekly: 4,
+                ..Default::default()
+            },
+            RetentionPolicy {
+                monthly: 2,
+                ..Default::default()
+            },
+            RetentionPolicy {
+                keep_last: 1,
+                daily: 2,
+                weekly: 3,
+                monthly: 3,
+            },
+        ];
+        for policy in policies {
+            let tmp = TempDir::new().unwrap();
+            let (dry, real) = (tmp.path().join("dry"), tmp.path().join("real"));
+            for dir in [&dry, &real] {
+                for &created in &times {
+                    let backup = dir.join(timestamped_name("data_bak", created));
+                    fs::create_dir_all(&backup).unwrap();
+                    fs::write(backup.join("a.txt"), b"alpha").unwrap();
+                }
+            }
+
+            // The dry run never creates the new backup; the real run has.
+            let new_backup = timestamped_name("data_bak", new);
+            let would = prune(
+                &dry.join(&new_backup),
+                &dry,
+                "data_bak",
+                &policy,
+                DryRunMode::DryRun,
+            )
+            .unwrap();
+            fs::create_dir(real.join(&new_backup)).unwrap();
+            let deleted = prune(
+                &real.join(&new_backup),
+                &real,
+                "data_bak",
+                &policy,
+                DryRunMode::Apply,
+            )
+            .unwrap();
+
+            assert!(!deleted.is_empty(), "{policy:?}");
+            assert_eq!(names(&would), names(&deleted), "{policy:?}");
+            assert_eq!(would[0].size().unwrap(), 5);
+            assert_eq!(list_backups(&dry, "data_bak").unwrap().len(), times.len());
+            assert_eq!(
+                list_backups(&real, "data_bak").unwrap().len(),
+                times.len() + 1 - deleted.len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_prune_after_verify_keeps_old_backups_when_new_is_damaged() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        let opts = crate::BackupOptions {
+            write_manifest: true,
+            ..Default::default()
+        };
+        let backups: Vec<_> = (1..=3)
+            .map(|day| tmp.path().join(timestamped_name("data_bak", at(day))))
+            .collect();
+        for backup in &backups {
+            crate::backup_directory_with(&src, backup, &opts).unwrap();
+        }
+        let newest = &backups[2];
+        fs::write(newest.join("a.txt"), b"bit rot").unwrap();
+
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            ..Default::default()
+        };
+        let err = prune_after_verify(
+            newest,
+            None,
+            tmp.path(),
+            "data_bak",
+            &policy,
+            DryRunMode::Apply,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("no older backups were pruned"));
+        assert!(backups.iter().all(|b| b.exists()));
+
+        fs::write(newest.join("a.txt"), b"alpha").unwrap();
+        let pruned = prune_after_verify(
+            newest,
+            None,
+            tmp.path(),
+            "data_bak",
+            &policy,
+            DryRunMode::Apply,
+        )
+        .unwrap();
+        assert_eq!(pruned.len(), 2);
+        assert!(newest.exists());
+    }
+
+    fn backups_at(times: &[DateTime<Utc>]) -> Vec<TimestampedBackup> {
+        times
+            .iter()
+            .map(|&created| TimestampedBackup {
+                path: PathBuf::from(timestamped_name("data_bak", created)),
+                created,
+            })
+            .collect()
+    }
+
+    fn kept(policy: RetentionPolicy, times: &[DateTime<Utc>]) -> Vec<DateTime<Utc>> {
+        let (kept, _) = policy.apply(backups_at(times));
+        kept.iter().map(|b| b.created).collect()
+    }
+
+    #[test]
+    fn test_gfs_retention() {
+        let day = |m: u32, d: u32, h: u32| Utc.with_ymd_and_hms(2026, m, d, h, 0, 0).unwrap();
+        // Two backups a day from 2026-07-01 to 2026-10-15, newest first.
+        let mut times = Vec::new();
+        let mut t = day(10, 15, 18);
+        while t >= day(7, 1, 0) {
+            times.push(t);
+            t -= chrono::Duration::hours(12);
+        }
+
+        let daily = RetentionPolicy {
+            daily: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(daily, &times),
+            [day(10, 15, 18), day(10, 14, 18), day(10, 13, 18)]
+        );
+
+        // 2026-10-15 is a Thursday; weeks start on Monday.
+        let weekly = RetentionPolicy {
+            weekly: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(weekly, &times),
+            [day(10, 15, 18), day(10, 11, 18), day(10, 4, 18)]
+        );
+
+        let monthly = RetentionPolicy {
+            monthly: 6,
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(monthly, &times),
+            [
+                day(10, 15, 18),
+                day(9, 30, 18),
+                day(8, 31, 18),
+                day(7, 31, 18)
+            ]
+        );
+
+        // Rules overlap: the newest backup counts towards all of them.
+        let gfs = RetentionPolicy {
+            keep_last: 2,
+            daily: 2,
+            weekly: 2,
+            monthly: 2,
+        };
+        assert_eq!(
+            kept(gfs, &times),
+            [
+                day(10, 15, 18),
+                day(10, 15, 6),
+                day(10, 14, 18),
+                day(10, 11, 18),
+                day(9, 30, 18),
+            ]
+        );
+        assert!(kept(RetentionPolicy::default(), &times).is_empty());
+    }
+}
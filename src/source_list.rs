@@ -0,0 +1,106 @@
+use crate::CopyObserver;
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Records the source path of every file a backup copied, one per line or
+/// NUL-terminated for `xargs -0`.
+pub struct SourceList {
+    path: PathBuf,
+    out: BufWriter<File>,
+    terminator: u8,
+}
+
+impl SourceList {
+    /// Creates (or truncates) the list at `path`.
+    pub fn create(path: &Path, null_separated: bool) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            out: BufWriter::new(file),
+            terminator: if null_separated { b'\0' } else { b'\n' },
+        })
+    }
+}
+
+impl CopyObserver for SourceList {
+    fn file_copied(&mut self, src: &Path, _name: &str) -> Result<()> {
+        self.out
+            .write_all(src.as_os_str().as_encoded_bytes())
+            .and_then(|()| self.out.write_all(&[self.terminator]))
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.out
+            .flush()
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{backup_directory_with, BackupOptions, ExcludePattern};
+    use std::{
+        collections::BTreeSet,
+        fs,
+        path::{Path, PathBuf},
+    };
+    use tempfile::TempDir;
+
+    fn files_under(dir: &Path, found: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files_under(&path, found);
+            } else {
+                found.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_source_list_matches_copied_files() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir_all(src_dir.join("sub/skipped")).unwrap();
+        fs::write(src_dir.join("a.txt"), b"a").unwrap();
+        fs::write(src_dir.join("sub/b.txt"), b"b").unwrap();
+        fs::write(src_dir.join("sub/c.tmp"), b"c").unwrap();
+        fs::write(src_dir.join("sub/skipped/d.txt"), b"d").unwrap();
+
+        let list = tmp.path().join("sources.lst");
+        let dst_dir = tmp.path().join("src_bak");
+        let opts = BackupOptions {
+            exclude: vec![
+                ExcludePattern::new("*.tmp").unwrap(),
+                ExcludePattern::new("skipped/").unwrap(),
+            ],
+            source_list: Some(list.clone()),
+            source_list_null: true,
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src_dir, &dst_dir, &opts).unwrap();
+
+        let listed: BTreeSet<PathBuf> = fs::read_to_string(&list)
+            .unwrap()
+            .split_terminator('\0')
+            .map(PathBuf::from)
+            .collect();
+        let mut in_backup = Vec::new();
+        files_under(&dst_dir, &mut in_backup);
+        let copied: BTreeSet<PathBuf> = in_backup
+            .iter()
+            .map(|p| src_dir.join(p.strip_prefix(&dst_dir).unwrap()))
+            .collect();
+        assert_eq!(listed.len() as u64, stats.files);
+        assert_eq!(listed, copied);
+        assert_eq!(
+            listed,
+            BTreeSet::from([src_dir.join("a.txt"), src_dir.join("sub/b.txt")])
+        );
+    }
+}
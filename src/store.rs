@@ -0,0 +1,225 @@
+use crate::{
+    manifest::{key_path, Manifest},
+    BackupStats, DryRunMode,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, File, FileTimes},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use tracing::info;
+
+/// Arguments of `rbak rehydrate`.
+#[derive(Debug, clap::Args)]
+pub struct RehydrateArgs {
+    /// Object store written by `rbak dir --store`
+    store: PathBuf,
+    /// Manifest of the backup to expand: a `.rbak.json` or the backup directory holding it
+    manifest: PathBuf,
+    /// Directory to rebuild the tree in
+    #[arg(short, long)]
+    dest: PathBuf,
+    /// Print what would be done without touching the filesystem
+    #[arg(short = 'n', long, alias = "no-action")]
+    dry_run: bool,
+}
+
+/// Content-addressed storage: each distinct file content is kept once, under
+/// `objects/<first two hex digits>/<BLAKE3 hash>`.
+#[derive(Debug)]
+pub struct ObjectStore {
+    root: PathBuf,
+}
+
+impl ObjectStore {
+    /// Opens the store at `root`, creating it unless on a dry run.
+    pub fn open(root: &Path, dry_run: DryRunMode) -> Result<Self> {
+        let objects = root.join("objects");
+        if dry_run.would_create(&objects) {
+            fs::create_dir_all(&objects)
+                .with_context(|| format!("creating store {}", root.display()))?;
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Where the object with the given hash lives. Fails unless `hash` is a
+    /// hex-encoded BLAKE3 hash, so a manifest cannot point outside the store.
+    pub fn object_path(&self, hash: &str) -> Result<PathBuf> {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("`{hash}` is not a BLAKE3 hash");
+        }
+        Ok(self.root.join("objects").join(&hash[..2]).join(hash))
+    }
+
+    /// Stores the contents of `src`, whose BLAKE3 hash is `hash`.
+    ///
+    /// Returns the number of bytes written, which is 0 if the store already
+    /// held the content.
+    pub fn put(&self, src: &Path, hash: &str, dry_run: DryRunMode) -> Result<u64> {
+        let object = self.object_path(hash)?;
+        if object.exists() || !dry_run.would_copy(src, &object) {
+            return Ok(0);
+        }
+        let parent = object.parent().unwrap_or(&self.root);
+        fs::create_dir_all(parent).context("creating object directory")?;
+        // Copy under a temporary name so an interrupted run never leaves a
+        // truncated object behind under its final name.
+        let partial = object.with_extension("partial");
+        let bytes =
+            fs::copy(src, &partial).with_context(|| format!("storing {}", src.display()))?;
+        fs::rename(&partial, &object).context("finalising stored object")?;
+        Ok(bytes)
+    }
+}
+
+/// Rebuilds the tree described by `manifest` from the objects in `store`.
+///
+/// Files get their recorded modification time back.
+pub fn rehydrate(
+    store: &ObjectStore,
+    manifest: &Manifest,
+    dest: &Path,
+    dry_run: DryRunMode,
+) -> Result<BackupStats> {
+    let started = Instant::now();
+    let mut stats = BackupStats::default();
+    for (key, entry) in &manifest.entries {
+        let object = store
+            .object_path(&entry.blake3)
+            .with_context(|| format!("reading the manifest entry for {key}"))?;
+        if !object.is_file() {
+            bail!(
+                "object {} for {key} is missing from the store",
+                entry.blake3
+            );
+        }
+        let target = dest.join(key_path(key)?);
+        let parent = target.parent().unwrap_or(dest);
+        if !parent.exists() && dry_run.would_create(parent) {
+            fs::create_dir_all(parent).context("creating directory")?;
+            stats.dirs += 1;
+        }
+        if dry_run.would_copy(&object, &target) {
+            stats.bytes +=
+                fs::copy(&object, &target).with_context(|| format!("rehydrating {key}"))?;
+            let mtime = UNIX_EPOCH + Duration::from_nanos(entry.mtime_ns);
+            File::options()
+                .write(true)
+                .open(&target)
+                .and_then(|f| f.set_times(FileTimes::new().set_modified(mtime)))
+                .with_context(|| format!("setting times on {}", target.display()))?;
+        }
+        stats.files += 1;
+    }
+    stats.duration = started.elapsed();
+    Ok(stats)
+}
+
+/// Runs `rbak rehydrate`.
+pub fn run(args: &RehydrateArgs) -> Result<()> {
+    let dry_run = DryRunMode::from_flag(args.dry_run);
+    if !args.store.join("objects").is_dir() {
+        bail!("{} is not an rbak object store", args.store.display());
+    }
+    let store = ObjectStore::open(&args.store, dry_run)?;
+    let manifest = Manifest::read(&args.manifest)?;
+    let stats = rehydrate(&store, &manifest, &args.dest, dry_run)?;
+    info!(
+        "Rehydrated {} files ({} bytes) into {}",
+        stats.files,
+        stats.bytes,
+        args.dest.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory_with, manifest::ManifestEntry, BackupOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_store_then_rehydrate_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub/deeper")).unwrap();
+        fs::write(src.join("a.txt"), b"same").unwrap();
+        fs::write(src.join("sub/copy.txt"), b"same").unwrap();
+        fs::write(src.join("sub/deeper/b.txt"), b"different").unwrap();
+
+        let store_dir = tmp.path().join("store");
+        let bak = tmp.path().join("data_bak");
+        let opts = BackupOptions {
+            store: Some(store_dir.clone()),
+            write_manifest: true,
+            ..Default::default()
+        };
+        let stats = backup_directory_with(&src, &bak, &opts).unwrap();
+        assert_eq!(stats.files, 3);
+        // Identical contents are stored once; the backup only holds the manifest.
+        assert_eq!(stats.bytes, 13);
+        assert!(!bak.join("a.txt").exists());
+
+        let store = ObjectStore::open(&store_dir, DryRunMode::Apply).unwrap();
+        let manifest = Manifest::read(&bak).unwrap();
+        let out = tmp.path().join("out");
+        rehydrate(&store, &manifest, &out, DryRunMode::Apply).unwrap();
+
+        for rel in ["a.txt", "sub/copy.txt", "sub/deeper/b.txt"] {
+            assert_eq!(
+                fs::read(out.join(rel)).unwrap(),
+                fs::read(src.join(rel)).unwrap()
+            );
+            let mtime = |p: &Path| fs::metadata(p).unwrap().modified().unwrap();
+            assert_eq!(mtime(&out.join(rel)), mtime(&src.join(rel)));
+        }
+    }
+
+    #[test]
+    fn test_rehydrate_rejects_crafted_manifests() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        let store_dir = tmp.path().join("store");
+        let bak = tmp.path().join("data_bak");
+        let opts = BackupOptions {
+            store: Some(store_dir.clone()),
+            write_manifest: true,
+            ..Default::default()
+        };
+        backup_directory_with(&src, &bak, &opts).unwrap();
+        let store = ObjectStore::open(&store_dir, DryRunMode::Apply).unwrap();
+        let manifest = Manifest::read(&bak).unwrap();
+        let entry = manifest.entries["a.txt"].clone();
+        fs::write(tmp.path().join("secret"), b"secret").unwrap();
+
+        let out = tmp.path().join("nested/out");
+        let escape = tmp.path().join("nested/escaped.txt");
+        let crafted = [
+            ("../escaped.txt", entry.blake3.clone()),
+            ("/tmp/rbak-escaped.txt", entry.blake3.clone()),
+            ("a/../../escaped.txt", entry.blake3.clone()),
+            ("stolen.txt", "../../../secret".to_string()),
+        ];
+        for (key, blake3) in crafted {
+            let mut manifest = manifest.clone();
+            let entry = ManifestEntry {
+                blake3,
+                ..entry.clone()
+            };
+            manifest.entries = [(key.to_string(), entry)].into();
+            assert!(
+                rehydrate(&store, &manifest, &out, DryRunMode::Apply).is_err(),
+                "{key}"
+            );
+        }
+        assert!(!escape.exists());
+        assert!(!Path::new("/tmp/rbak-escaped.txt").exists());
+        assert!(!out.join("stolen.txt").exists());
+    }
+}
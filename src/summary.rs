@@ -0,0 +1,162 @@
+use crate::BackupStats;
+use clap::ValueEnum;
+use serde_json::json;
+
+/// Layouts for the end-of-run summary selected with `--summary-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SummaryFormat {
+    /// A sentence for people reading the terminal
+    Human,
+    /// One stable `key=value` line for grepping logs
+    Compact,
+    /// A JSON object
+    Json,
+}
+
+impl SummaryFormat {
+    /// Renders the summary of a backup run.
+    pub fn render(self, stats: &BackupStats) -> String {
+        self.render_as("Backup", stats)
+    }
+
+    /// Renders a summary whose human form starts with `operation`
+    /// (`Backup`, `Restore`); the other formats do not name it.
+    pub fn render_as(self, operation: &str, stats: &BackupStats) -> String {
+        let status = if stats.timed_out { "partial" } else { "ok" };
+        let duration_ms = stats.duration.as_millis();
+        match self {
+            SummaryFormat::Human => {
+                format!(
+                "{operation} {}: {} in {:.1}s ({}); {} in {}, {} skipped, {} unchanged, {} deleted",
+                if stats.timed_out { "stopped at max runtime" } else { "complete" },
+                format_size(stats.bytes as f64),
+                stats.duration.as_secs_f64(),
+                format_rate(stats.throughput_bps()),
+                count(stats.files, "file", "files"),
+                count(stats.dirs, "directory", "directories"),
+                stats.skipped,
+                stats.unchanged,
+                stats.deleted
+            )
+            }
+            SummaryFormat::Compact => format!(
+                "{status} files={} dirs={} bytes={} skipped={} duration_ms={duration_ms}",
+                stats.files, stats.dirs, stats.bytes, stats.skipped
+            ),
+            SummaryFormat::Json => json!({
+                "status": status,
+                "files": stats.files,
+                "dirs": stats.dirs,
+                "bytes": stats.bytes,
+                "skipped": stats.skipped,
+                "unchanged": stats.unchanged,
+                "deleted": stats.deleted,
+                "duration_ms": duration_ms,
+                "throughput_bps": stats.throughput_bps(),
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// Formats a transfer rate with a decimal unit: `1.23 GB/s`, `456 MB/s`,
+/// `123 KB/s` or `12 B/s`.
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{}/s", format_size(bytes_per_sec))
+}
+
+/// Formats a byte count with a decimal unit: `1.23 GB`, `4.2 GB`, `456 MB`,
+/// `123 KB` or `12 B`.
+pub fn format_size(bytes: f64) -> String {
+    if bytes >= 1e9 {
+        let gb = format!("{:.2}", bytes / 1e9);
+        format!("{} GB", gb.trim_end_matches('0').trim_end_matches('.'))
+    } else if bytes >= 1e6 {
+        format!("{:.0} MB", bytes / 1e6)
+    } else if bytes >= 1e3 {
+        format!("{:.0} KB", bytes / 1e3)
+    } else {
+        format!("{bytes:.0} B")
+    }
+}
+
+/// `n` followed by the singular or plural noun, as `1 file` or `3 files`.
+fn count(n: u64, singular: &str, plural: &str) -> String {
+    format!("{n} {}", if n == 1 { singular } else { plural })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory_with, BackupOptions, ExcludePattern};
+    use std::{fs, time::Duration};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_compact_summary_for_known_run() {
+        let tmp = TempDir::new().unwrap();
+        let src_dir = tmp.path().join("src");
+        fs::create_dir_all(src_dir.join("sub")).unwrap();
+        fs::write(src_dir.join("a.txt"), b"12345").unwrap();
+        fs::write(src_dir.join("sub/b.txt"), b"1234567").unwrap();
+        fs::write(src_dir.join("sub/c.tmp"), b"skip me").unwrap();
+
+        let opts = BackupOptions {
+            exclude: vec![ExcludePattern::new("*.tmp").unwrap()],
+            ..Default::default()
+        };
+        let mut stats =
+            backup_directory_with(&src_dir, &tmp.path().join("src_bak"), &opts).unwrap();
+        stats.duration = Duration::from_millis(842);
+
+        assert_eq!(
+            SummaryFormat::Compact.render(&stats),
+            "ok files=2 dirs=2 bytes=12 skipped=1 duration_ms=842"
+        );
+    }
+
+    #[test]
+    fn test_json_summary_reports_partial_runs() {
+        let stats = BackupStats {
+            files: 1,
+            timed_out: true,
+            ..Default::default()
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&SummaryFormat::Json.render(&stats)).unwrap();
+        assert_eq!(json["status"], "partial");
+        assert_eq!(json["files"], 1);
+    }
+
+    #[test]
+    fn test_throughput_in_summaries() {
+        let stats = BackupStats {
+            files: 3,
+            dirs: 1,
+            bytes: 4_200_000_000,
+            duration: Duration::from_millis(3410),
+            ..Default::default()
+        };
+        assert_eq!(
+            SummaryFormat::Human.render(&stats),
+            "Backup complete: 4.2 GB in 3.4s (1.23 GB/s); 3 files in 1 directory, 0 skipped, 0 unchanged, 0 deleted"
+        );
+        let single = BackupStats {
+            files: 1,
+            dirs: 2,
+            bytes: 3_000_000_000,
+            ..stats
+        };
+        assert!(SummaryFormat::Human
+            .render(&single)
+            .starts_with("Backup complete: 3 GB in 3.4s (880 MB/s); 1 file in 2 directories,"));
+        let json: serde_json::Value =
+            serde_json::from_str(&SummaryFormat::Json.render(&stats)).unwrap();
+        assert_eq!(json["throughput_bps"], 4_200_000_000.0 / 3.41);
+
+        assert_eq!(format_rate(456_400_000.0), "456 MB/s");
+        assert_eq!(format_rate(123_000.0), "123 KB/s");
+        assert_eq!(format_rate(12.0), "12 B/s");
+        assert_eq!(BackupStats::default().throughput_bps(), 0.0);
+    }
+}
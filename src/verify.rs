@@ -0,0 +1,387 @@
+use crate::{
+    archive::{open_archive, TAR_GZ_EXTENSION},
+    compress::Compression,
+    manifest::{key_path, manifest_key, Manifest, MANIFEST_NAME},
+    store::ObjectStore,
+    DryRunMode,
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+/// Arguments of `rbak verify`.
+#[derive(Debug, clap::Args)]
+pub struct VerifyArgs {
+    /// Backup to check: a `_bak` directory with a manifest, or a `.tar.gz`
+    /// archive written with --embed-manifest
+    backup: PathBuf,
+    /// Object store holding the contents of a `dir --store` backup
+    #[arg(long, value_name = "DIR")]
+    store: Option<PathBuf>,
+}
+
+/// A backed-up file that no longer matches its manifest entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// Neither a plain nor a compressed copy exists.
+    Missing(String),
+    /// The contents hash differently from what was recorded.
+    Corrupt(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing(key) => write!(f, "{key} is missing"),
+            Problem::Corrupt(key) => write!(f, "{key} does not match its checksum"),
+        }
+    }
+}
+
+/// Result of checking a backup against its manifest.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of manifest entries checked.
+    pub checked: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Re-hashes every file listed in the manifest of the backup in `dir`.
+///
+/// Compressed copies are hashed after decompression. Backups made with
+/// `--store` are checked against the objects in `store`. Returns `None` if
+/// the backup has no manifest to check against.
+pub fn verify_backup(dir: &Path, store: Option<&ObjectStore>) -> Result<Option<VerifyReport>> {
+    let Some(manifest) = Manifest::load(dir)? else {
+        return Ok(None);
+    };
+    let mut report = VerifyReport::default();
+    for (key, entry) in &manifest.entries {
+        report.checked += 1;
+        let Some((path, compression)) = locate(dir, key, &entry.blake3, store) else {
+            report.problems.push(Problem::Missing(key.clone()));
+            continue;
+        };
+        let mut reader = BufReader::new(
+            File::open(&path).with_context(|| format!("opening {}", path.display()))?,
+        );
+        let mut hasher = blake3::Hasher::new();
+        match compression {
+            Some(algo) => {
+                // A stream that fails to decode is as corrupt as a wrong hash.
+                if algo
+                    .compressor(None)?
+                    .decompress(&mut reader, &mut hasher)
+                    .is_err()
+                {
+                    report.problems.push(Problem::Corrupt(key.clone()));
+                    continue;
+                }
+            }
+            None => {
+                io::copy(&mut reader, &mut hasher)
+                    .with_context(|| format!("hashing {}", path.display()))?;
+            }
+        }
+        if hasher.finalize().to_hex().as_str() != entry.blake3 {
+            report.problems.push(Problem::Corrupt(key.clone()));
+        }
+    }
+    Ok(Some(report))
+}
+
+/// Re-hashes every file in the `.tar.gz` at `archive` against the manifest
+/// embedded in it, streaming the archive without extracting anything.
+///
+/// Returns `None` if the archive has no embedded manifest. Files in the
+/// archive but not in the manifest are ignored.
+pub fn verify_archive(archive: &Path) -> Result<Option<VerifyReport>> {
+    let mut tar = open_archive(archive)?;
+    let mut hashes = HashMap::new();
+    let mut manifest: Option<Manifest> = None;
+    for entry in tar.entries().context("reading archive")? {
+        let mut entry = entry.context("reading archive entry")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().context("reading entry path")?.into_owned();
+        if path == Path::new(MANIFEST_NAME) {
+            manifest =
+                Some(serde_json::from_reader(&mut entry).context("parsing embedded manifest")?);
+            continue;
+        }
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut entry, &mut hasher)
+            .with_context(|| format!("reading {} from the archive", path.display()))?;
+        hashes.insert(manifest_key(&path), hasher.finalize().to_hex().to_string());
+    }
+
+    let Some(manifest) = manifest else {
+        return Ok(None);
+    };
+    let mut report = VerifyReport::default();
+    for (key, entry) in &manifest.entries {
+        report.checked += 1;
+        match hashes.get(key) {
+            None => report.problems.push(Problem::Missing(key.clone())),
+            Some(hash) if *hash != entry.blake3 => {
+                report.problems.push(Problem::Corrupt(key.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(Some(report))
+}
+
+/// Finds the copy of `key` in a backup, and the compression it was written with.
+pub(crate) fn locate(
+    dir: &Path,
+    key: &str,
+    hash: &str,
+    store: Option<&ObjectStore>,
+) -> Option<(PathBuf, Option<Compression>)> {
+    let plain = dir.join(key_path(key).ok()?);
+    if plain.is_file() {
+        return Some((plain, None));
+    }
+    for algo in Compression::value_variants() {
+        let ext = algo.compressor(None).ok()?.extension();
+        let compressed = dir.join(format!("{key}.{ext}"));
+        if compressed.is_file() {
+            return Some((compressed, Some(*algo)));
+        }
+    }
+    store
+        .and_then(|store| store.object_path(hash).ok())
+        .filter(|object| object.is_file())
+        .map(|object| (object, None))
+}
+
+/// Verifies the backup in `dir`, returning a description of what is wrong
+/// with it, or `None` if it is intact.
+pub fn describe_damage(dir: &Path, store: Option<&ObjectStore>) -> Result<Option<String>> {
+    Ok(match verify_backup(dir, store)? {
+        Some(report) if report.is_intact() => None,
+        Some(report) => Some(format!(
+            "{} of {} files are damaged (first: {})",
+            report.problems.len(),
+            report.checked,
+            report.problems[0]
+        )),
+        None => {
+            Some("it has no manifest to check against (back it up with --manifest)".to_string())
+        }
+    })
+}
+
+/// Refuses to continue if the backup in `dir` fails verification, unless
+/// `force` is set, in which case the problems are only logged.
+pub fn ensure_intact(dir: &Path, store: Option<&ObjectStore>, force: bool) -> Result<()> {
+    let Some(problem) = describe_damage(dir, store)? else {
+        return Ok(());
+    };
+    if force {
+        warn!(
+            "Previous backup {} failed verification: {problem}",
+            dir.display()
+        );
+        return Ok(());
+    }
+    bail!(
+        "previous backup {} failed verification: {problem}; pass --force-backup-despite-corrupt-previous to back up anyway",
+        dir.display()
+    )
+}
+
+/// Runs `rbak verify`.
+pub fn run(args: &VerifyArgs) -> Result<()> {
+    let is_archive = args
+        .backup
+        .to_string_lossy()
+        .ends_with(&format!(".{TAR_GZ_EXTENSION}"));
+    let report = if is_archive {
+        verify_archive(&args.backup)?.with_context(|| {
+            format!(
+                "{} has no embedded manifest (back it up with --embed-manifest)",
+                args.backup.display()
+            )
+        })?
+    } else {
+        let store = match &args.store {
+            Some(root) if !root.join("objects").is_dir() => {
+                bail!("{} is not an rbak object store", root.display())
+            }
+            Some(root) => Some(ObjectStore::open(root, DryRunMode::Apply)?),
+            None => None,
+        };
+        verify_backup(&args.backup, store.as_ref())?.with_context(|| {
+            format!(
+                "{} has no manifest to check against (back it up with --manifest)",
+                args.backup.display()
+            )
+        })?
+    };
+
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    if !report.is_intact() {
+        bail!(
+            "{} of {} files in {} are damaged",
+            report.problems.len(),
+            report.checked,
+            args.backup.display()
+        );
+    }
+    info!(
+        "Verified {} files in {}",
+        report.checked,
+        args.backup.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backup_directory_with, BackupOptions};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_verify_backup_detects_damage() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("sub/b.txt"), b"beta").unwrap();
+        fs::write(src.join("c.txt"), b"gamma").unwrap();
+        let bak = tmp.path().join("data_bak");
+        let opts = BackupOptions {
+            write_manifest: true,
+            compressor: Some(Compression::Brotli.compressor(Some(1)).unwrap()),
+            ..Default::default()
+        };
+        backup_directory_with(&src, &bak, &opts).unwrap();
+
+        let report = verify_backup(&bak, None).unwrap().unwrap();
+        assert_eq!((report.checked, report.is_intact()), (3, true));
+        assert!(ensure_intact(&bak, None, false).is_ok());
+
+        fs::write(bak.join("a.txt.br"), b"not brotli at all").unwrap();
+        fs::remove_file(bak.join("sub/b.txt.br")).unwrap();
+        let report = verify_backup(&bak, None).unwrap().unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::Corrupt("a.txt".to_string()),
+                Problem::Missing("sub/b.txt".to_string()),
+            ]
+        );
+
+        let err = ensure_intact(&bak, None, false).unwrap_err();
+        assert!(err.to_string().contains("2 of 3 files are damaged"));
+        assert!(ensure_intact(&bak, None, true).is_ok());
+        assert!(ensure_intact(&src, None, false).is_err());
+    }
+
+    #[test]
+    fn test_verify_archive_with_embedded_manifest() {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("sub/b.txt"), b"beta").unwrap();
+        let opts = BackupOptions {
+            write_manifest: true,
+            ..Default::default()
+        };
+        let (archive, _) =
+            crate::archive::archive_directory(&src, &tmp.path().join("data_bak"), &opts, false)
+                .unwrap();
+
+        let report = verify_archive(&archive).unwrap().unwrap();
+        assert_eq!((report.checked, report.is_intact()), (2, true));
+
+        // Restoring leaves the embedded manifest out.
+        let out = tmp.path().join("out");
+        let stats = crate::archive::extract_archive(&archive, &out, Default::default()).unwrap();
+        assert_eq!(stats.files, 2);
+        assert!(!out.join(MANIFEST_NAME).exists());
+
+        let plain = tmp.path().join("plain.tar.gz");
+        crate::archive::create_archive(&src, &src, &plain, &BackupOptions::default(), false)
+            .unwrap();
+        assert_eq!(verify_archive(&plain).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_archive_detects_corruption() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("data");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("b.txt"), b"beta").unwrap();
+        let opts = BackupOptions {
+            write_manifest: true,
+            ..Default::default()
+        };
+        let (archive, _) =
+            crate::archive::archive_directory(&src, &tmp.path().join("data_bak"), &opts, false)
+                .unwrap();
+
+        // Re-pack the archive with one file's contents swapped and another dropped.
+        let tampered = tmp.path().join("tampered.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&tampered).unwrap(),
+            Compression::fast(),
+        ));
+        let mut tar = open_archive(&archive).unwrap();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let mut header = entry.header().clone();
+            match path.to_str().unwrap() {
+                "a.txt" => {
+                    builder
+                        .append_data(&mut header, &path, &b"ALPHA"[..])
+                        .unwrap();
+                }
+                "b.txt" => {}
+                _ => builder.append_data(&mut header, &path, &mut entry).unwrap(),
+            }
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let report = verify_archive(&tampered).unwrap().unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::Corrupt("a.txt".to_string()),
+                Problem::Missing("b.txt".to_string()),
+            ]
+        );
+
+        // A damaged compressed stream cannot be read at all.
+        let mut bytes = fs::read(&archive).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        let truncated = tmp.path().join("truncated.tar.gz");
+        fs::write(&truncated, bytes).unwrap();
+        assert!(verify_archive(&truncated).is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use std::{fs, process::Command};
+use tempfile::TempDir;
+
+#[test]
+fn test_hash_file_stdout_holds_only_hash_lines() {
+    let tmp = TempDir::new().unwrap();
+    let src = tmp.path().join("data");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("a.txt"), b"alpha").unwrap();
+    fs::write(src.join("b.txt"), b"beta").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rbak"))
+        .current_dir(tmp.path())
+        .env("RUST_LOG", "info")
+        .args(["dir", "data", "--hash-file", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "{stdout}");
+    for line in lines {
+        let (hash, path) = line.split_once("  ").unwrap();
+        assert_eq!(hash.len(), 64);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert!(path == "a.txt" || path == "b.txt");
+    }
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Backup complete"), "{stderr}");
+    assert!(stderr.contains("Backing up directory"), "{stderr}");
+}